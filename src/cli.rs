@@ -1,26 +1,225 @@
-use clap::{command, ArgAction, Parser};
+use std::{path::PathBuf, time::Duration};
 
-#[derive(Parser, Debug)]
+use clap::{command, ArgAction, Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::parse_duration;
+
+/// What SFTP clients are allowed to do
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum SftpMode {
+    #[default]
+    ReadWrite,
+    // Download only
+    ReadOnly,
+    // Upload new files only: no listing, no reading, no overwriting
+    DropBox,
+}
+
+impl SftpMode {
+    pub fn can_read(self) -> bool {
+        self != SftpMode::DropBox
+    }
+
+    /// Create files and directories
+    pub fn can_write(self) -> bool {
+        self != SftpMode::ReadOnly
+    }
+
+    /// Change or remove existing files
+    pub fn can_modify(self) -> bool {
+        self == SftpMode::ReadWrite
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
 #[command(name = env!("CARGO_PKG_NAME"), author, about, version, long_about = None)]
 pub struct Command {
-    #[arg(short, long, action = ArgAction::Count)]
+    #[command(subcommand)]
+    pub action: Option<Action>,
+    // Configuration file to use. Default to $XDG_CONFIG_HOME/quickssh/config.toml if it exists
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    #[arg(short, long, action = ArgAction::Count, global = true)]
     pub verbose: u8,
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub user: Option<String>,
-    #[arg(long, alias = "pass")]
+    #[arg(long, alias = "pass", global = true)]
     pub password: Option<String>,
     // public keys that can be used to connect
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub pubkey: Vec<String>,
+    // Reload authorized_keys and the configuration file when they change (they are also reloaded on SIGHUP)
+    #[arg(long, global = true)]
+    pub watch_keys: bool,
     // Default shell that connected users will have. Default to the shell used to start the quickssh server process
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub shell: Option<String>,
     // Disable shell
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub no_shell: bool,
     // Disable SFTP submodule
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub no_sftp: bool,
-    #[arg(short, long, default_value_t = 2222)]
-    pub port: u16,
+    // Confine SFTP to this directory, shown to clients as /. Applies to every user without their own sftp_root
+    #[arg(long, global = true)]
+    pub sftp_root: Option<PathBuf>,
+    // What SFTP clients can do. Applies to every user without their own sftp_mode. Default to read-write
+    #[arg(long, value_enum, global = true)]
+    pub sftp_mode: Option<SftpMode>,
+    // Show a directory to SFTP clients at a path, as /path=directory, or /path=memory: for an in-memory scratch
+    // directory. Can be repeated. Without it, SFTP clients see the whole filesystem
+    #[arg(long, global = true)]
+    pub sftp_mount: Vec<String>,
+    // Host private key (OpenSSH or PKCS#8), generated if missing. Can be repeated to offer several key types.
+    // Default to $XDG_CONFIG_HOME/quickssh/private.key
+    #[arg(long, global = true)]
+    pub host_key: Vec<PathBuf>,
+    // Read the passphrase protecting the host keys from this file
+    #[arg(long, global = true)]
+    pub host_key_passphrase_file: Option<PathBuf>,
+    // Read the passphrase protecting the host keys from this environment variable
+    #[arg(long, global = true)]
+    pub host_key_passphrase_env: Option<String>,
+    // Ask for the passphrase protecting the host keys on the terminal
+    #[arg(long, global = true)]
+    pub host_key_passphrase_prompt: bool,
+    // Generate a host key in memory instead of loading one. Clients will see a new key on every start
+    #[arg(long, global = true)]
+    pub ephemeral_host_key: bool,
+    // Never write to the filesystem: missing host keys are kept in memory only, and the options that
+    // write files (--control-socket, --audit-log to a file, --record-dir) are refused
+    #[arg(long, global = true)]
+    pub read_only: bool,
+    // Unix socket used by the sessions and kick subcommands to talk to a running server.
+    // Default to $XDG_RUNTIME_DIR/quickssh/control.sock
+    #[arg(long, global = true)]
+    pub control_socket: Option<PathBuf>,
+    // Write a JSON audit trail of connections, authentication attempts and file changes to this file,
+    // or to syslog when set to "syslog"
+    #[arg(long, global = true)]
+    pub audit_log: Option<String>,
+    // Record interactive shells in this directory, in the asciicast v2 format
+    #[arg(long, global = true)]
+    pub record_dir: Option<PathBuf>,
+    // Also record what users type (including passwords typed in the shell)
+    #[arg(long, global = true)]
+    pub record_input: bool,
+    // Keep the shell of a dropped connection running this long, so it can be reattached (e.g. 10m)
+    #[arg(long, value_parser = parse_duration, global = true)]
+    pub detach_grace: Option<Duration>,
+    // Port to listen on, on all interfaces. Default to 2222
+    #[arg(short, long, global = true)]
+    pub port: Option<u16>,
+    // Address to listen on, e.g. 127.0.0.1:2222. Can be repeated, overrides --port
+    #[arg(long, global = true)]
+    pub listen: Vec<String>,
+    // Serve a single connection over stdin/stdout instead of listening on a port (e.g. as a ProxyCommand)
+    #[arg(long, global = true)]
+    pub inetd: bool,
+    // Exit after the first authenticated connection ends
+    #[arg(long, global = true)]
+    pub once: bool,
+    // Exit when no session has been open for this long (e.g. 90s, 15m, 2h)
+    #[arg(long, value_parser = parse_duration, global = true)]
+    pub idle_exit: Option<Duration>,
+    // Stop the server after this long, disconnecting connected sessions
+    #[arg(long, value_parser = parse_duration, global = true)]
+    pub lifetime: Option<Duration>,
+    // On SIGTERM/SIGINT, how long to wait for open sessions to finish before closing them. Default to 30s
+    #[arg(long, value_parser = parse_duration, global = true)]
+    pub grace_period: Option<Duration>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Action {
+    // Run the SSH server (default)
+    Serve,
+    // Validate the configuration file
+    Check,
+    // Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigAction),
+    // Print the host key fingerprints, known_hosts lines and SSHFP records
+    Fingerprint,
+    // Manage the host keys
+    #[command(subcommand)]
+    HostKey(HostKeyAction),
+    // Manage the public keys allowed to log in as the main user ($XDG_CONFIG_HOME/quickssh/authorized_keys)
+    #[command(subcommand)]
+    Keys(KeysAction),
+    // List the connections of a running server
+    Sessions,
+    // Disconnect a connection of a running server
+    Kick {
+        // Connection id, as shown by the sessions subcommand
+        id: usize,
+        // Message sent to the client
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    // Play a session recorded with --record-dir in the terminal
+    Replay {
+        file: PathBuf,
+        // Playback speed, e.g. 2 to play twice as fast
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        // Shorten pauses longer than this many seconds
+        #[arg(long)]
+        idle_limit: Option<f64>,
+    },
+    // Write a message to every interactive session of a running server
+    Broadcast {
+        #[arg(required = true, num_args = 1..)]
+        message: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum KeysAction {
+    // Authorize a public key, e.g. "ssh-ed25519 AAAA... me@laptop"
+    Add {
+        #[arg(required = true, num_args = 1..)]
+        key: Vec<String>,
+    },
+    // List the authorized public keys
+    List,
+    // Remove a public key, selected by its number in `keys list`, its fingerprint, its comment or the key itself
+    Remove {
+        key: String,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum HostKeyAction {
+    // Print the public host keys
+    Show,
+    // Replace the host keys with new ones. Clients will get a host key changed warning
+    Regenerate {
+        // Confirm the host keys should be replaced
+        #[arg(long)]
+        yes: bool,
+    },
+    // Change the passphrase of the host keys. The current one is read using the --host-key-passphrase-* options.
+    // Keys are rewritten in PKCS#8 format
+    Rekey {
+        // Read the new passphrase from this file. Default to asking on the terminal
+        #[arg(long)]
+        new_passphrase_file: Option<PathBuf>,
+        // Read the new passphrase from this environment variable
+        #[arg(long)]
+        new_passphrase_env: Option<String>,
+        // Store the host keys unencrypted
+        #[arg(long)]
+        no_passphrase: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigAction {
+    // Validate the configuration file
+    Check,
+    // Print the effective configuration, after applying command line flags. Passwords are redacted
+    Dump,
 }
@@ -0,0 +1,317 @@
+use std::{
+    fmt::Display,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use toml::Spanned;
+
+use crate::{
+    cli::{Command, SftpMode},
+    utils::format_duration,
+};
+
+/// Content of the configuration file. Command line flags take precedence over it
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pubkeys: Vec<Spanned<String>>,
+    pub watch_keys: Option<bool>,
+    pub shell: Option<String>,
+    pub no_shell: Option<bool>,
+    pub no_sftp: Option<bool>,
+    // default SFTP root of the users, the main one included
+    pub sftp_root: Option<String>,
+    pub sftp_mode: Option<SftpMode>,
+    // /path=directory or /path=memory:
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sftp_mounts: Vec<Spanned<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub host_keys: Vec<Spanned<String>>,
+    pub host_key_passphrase_file: Option<String>,
+    pub host_key_passphrase_env: Option<String>,
+    pub host_key_passphrase_prompt: Option<bool>,
+    pub ephemeral_host_key: Option<bool>,
+    pub read_only: Option<bool>,
+    pub control_socket: Option<String>,
+    // file path, or "syslog"
+    pub audit_log: Option<String>,
+    pub record_dir: Option<String>,
+    pub record_input: Option<bool>,
+    pub detach_grace: Option<Spanned<String>>,
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<Spanned<String>>,
+    pub inetd: Option<bool>,
+    pub once: Option<bool>,
+    pub idle_exit: Option<Spanned<String>>,
+    pub lifetime: Option<Spanned<String>>,
+    pub grace_period: Option<Spanned<String>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<UserConfig>,
+    pub log: LogConfig,
+}
+
+/// Additional user that can log in
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: Spanned<String>,
+    pub password: Option<String>,
+    // check the password against the system account of the same name
+    #[serde(default)]
+    pub su: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pubkeys: Vec<Spanned<String>>,
+    pub sftp_root: Option<String>,
+    pub sftp_mode: Option<SftpMode>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub verbose: Option<u8>,
+}
+
+/// Where the configuration comes from, used to report the position of invalid values
+pub struct Origin {
+    pub path: Option<PathBuf>,
+    text: String,
+}
+
+impl Origin {
+    /// Values coming from the command line have an empty span
+    pub fn error(&self, span: Range<usize>, msg: impl Display) -> anyhow::Error {
+        match self.path {
+            Some(ref path) if !span.is_empty() => {
+                let before = &self.text[..span.start.min(self.text.len())];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+                anyhow::anyhow!("{}:{line}:{column}: {msg}", path.display())
+            }
+            _ => anyhow::anyhow!("command line: {msg}"),
+        }
+    }
+}
+
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    let xdg = xdg::BaseDirectories::with_prefix("quickssh")?;
+    Ok(xdg.get_config_home().join("config.toml"))
+}
+
+/// Load the configuration file at `path`, or at the default location if it exists
+pub fn load(path: Option<&Path>) -> anyhow::Result<(Config, Origin)> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => {
+            let path = default_path()?;
+            if !path.exists() {
+                return Ok((
+                    Config::default(),
+                    Origin {
+                        path: None,
+                        text: String::new(),
+                    },
+                ));
+            }
+            path
+        }
+    };
+
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read configuration file {}", path.display()))?;
+    let origin = Origin {
+        path: Some(path),
+        text,
+    };
+
+    let config = toml::from_str(&origin.text).map_err(|err| {
+        let span = err.span().unwrap_or(0..1);
+        origin.error(span, err.message())
+    })?;
+    Ok((config, origin))
+}
+
+fn from_cli<T>(value: T) -> Spanned<T> {
+    Spanned::new(0..0, value)
+}
+
+fn merge_flag(value: &mut Option<bool>, flag: bool) {
+    if flag {
+        *value = Some(true);
+    }
+}
+
+impl Config {
+    /// Hide the passwords, so `config dump` can be shown or pasted without leaking them
+    pub fn redact_passwords(&mut self) {
+        let passwords = std::iter::once(&mut self.password)
+            .chain(self.users.iter_mut().map(|user| &mut user.password));
+        for password in passwords.flatten() {
+            *password = "<redacted>".to_string();
+        }
+    }
+
+    /// Apply the command line flags on top of the file values
+    pub fn merge(&mut self, cmd: &Command) {
+        if cmd.verbose > 0 {
+            self.log.verbose = Some(cmd.verbose);
+        }
+        if cmd.user.is_some() {
+            self.user = cmd.user.clone();
+        }
+        if cmd.password.is_some() {
+            self.password = cmd.password.clone();
+        }
+        self.pubkeys
+            .extend(cmd.pubkey.iter().map(|key| from_cli(key.clone())));
+        merge_flag(&mut self.watch_keys, cmd.watch_keys);
+        if cmd.shell.is_some() {
+            self.shell = cmd.shell.clone();
+        }
+        merge_flag(&mut self.no_shell, cmd.no_shell);
+        merge_flag(&mut self.no_sftp, cmd.no_sftp);
+        if let Some(ref root) = cmd.sftp_root {
+            self.sftp_root = Some(root.display().to_string());
+        }
+        if cmd.sftp_mode.is_some() {
+            self.sftp_mode = cmd.sftp_mode;
+        }
+        if !cmd.sftp_mount.is_empty() {
+            self.sftp_mounts = cmd
+                .sftp_mount
+                .iter()
+                .map(|mount| from_cli(mount.clone()))
+                .collect();
+        }
+        if !cmd.host_key.is_empty() {
+            self.host_keys = cmd
+                .host_key
+                .iter()
+                .map(|path| from_cli(path.display().to_string()))
+                .collect();
+        }
+        // the passphrase sources are exclusive, so one given on the command line replaces the file's
+        if cmd.host_key_passphrase_file.is_some()
+            || cmd.host_key_passphrase_env.is_some()
+            || cmd.host_key_passphrase_prompt
+        {
+            self.host_key_passphrase_file = cmd
+                .host_key_passphrase_file
+                .as_ref()
+                .map(|path| path.display().to_string());
+            self.host_key_passphrase_env = cmd.host_key_passphrase_env.clone();
+            self.host_key_passphrase_prompt = cmd.host_key_passphrase_prompt.then_some(true);
+        }
+        merge_flag(&mut self.ephemeral_host_key, cmd.ephemeral_host_key);
+        merge_flag(&mut self.read_only, cmd.read_only);
+        if let Some(ref path) = cmd.control_socket {
+            self.control_socket = Some(path.display().to_string());
+        }
+        if cmd.audit_log.is_some() {
+            self.audit_log = cmd.audit_log.clone();
+        }
+        if let Some(ref dir) = cmd.record_dir {
+            self.record_dir = Some(dir.display().to_string());
+        }
+        merge_flag(&mut self.record_input, cmd.record_input);
+        if let Some(detach_grace) = cmd.detach_grace {
+            self.detach_grace = Some(from_cli(format_duration(detach_grace)));
+        }
+        if cmd.port.is_some() {
+            self.port = cmd.port;
+        }
+        if !cmd.listen.is_empty() {
+            self.listen = cmd
+                .listen
+                .iter()
+                .map(|addr| from_cli(addr.clone()))
+                .collect();
+        }
+        merge_flag(&mut self.inetd, cmd.inetd);
+        merge_flag(&mut self.once, cmd.once);
+        if let Some(idle_exit) = cmd.idle_exit {
+            self.idle_exit = Some(from_cli(format_duration(idle_exit)));
+        }
+        if let Some(lifetime) = cmd.lifetime {
+            self.lifetime = Some(from_cli(format_duration(lifetime)));
+        }
+        if let Some(grace_period) = cmd.grace_period {
+            self.grace_period = Some(from_cli(format_duration(grace_period)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin(text: &str) -> Origin {
+        Origin {
+            path: Some(PathBuf::from("config.toml")),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_value() {
+        let text = "port = 22\nlisten = [\"a\", \"b:x\"]\n";
+        let origin = origin(text);
+        assert_eq!(
+            origin.error(0..4, "invalid").to_string(),
+            "config.toml:1:1: invalid"
+        );
+        let start = text.find("\"b:x\"").unwrap();
+        assert_eq!(
+            origin.error(start..start + 5, "invalid").to_string(),
+            "config.toml:2:16: invalid"
+        );
+    }
+
+    #[test]
+    fn command_line_errors_have_no_position() {
+        assert_eq!(
+            origin("port = 22\n").error(0..0, "invalid").to_string(),
+            "command line: invalid"
+        );
+        let origin = Origin {
+            path: None,
+            text: String::new(),
+        };
+        assert_eq!(
+            origin.error(3..5, "invalid").to_string(),
+            "command line: invalid"
+        );
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_key() {
+        let path =
+            std::env::temp_dir().join(format!("quickssh-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "port = 22\nnope = 1\n").unwrap();
+        let err = load(Some(&path)).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            err.starts_with(&format!("{}:2:1: ", path.display())),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn dump_hides_passwords() {
+        let mut config: Config = toml::from_str(
+            "password = \"secret1\"\n[[users]]\nname = \"bob\"\npassword = \"secret2\"\n[[users]]\nname = \"eve\"\n",
+        )
+        .unwrap();
+        config.redact_passwords();
+        let dump = toml::to_string_pretty(&config).unwrap();
+        assert!(!dump.contains("secret"), "{dump}");
+        assert_eq!(config.users[0].password.as_deref(), Some("<redacted>"));
+        assert_eq!(config.users[1].password, None);
+    }
+}
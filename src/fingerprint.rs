@@ -0,0 +1,328 @@
+use std::fmt::Write;
+
+use data_encoding::{BASE64, BASE64_NOPAD, HEXLOWER};
+use md5::Md5;
+use russh_keys::{
+    key::{KeyPair, PublicKey},
+    PublicKeyBase64,
+};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// Split a SSH public key blob into its strings. The first one is the key type (e.g. ssh-ed25519)
+fn blob_strings(blob: &[u8]) -> Vec<&[u8]> {
+    let mut strings = vec![];
+    let mut rest = blob;
+    while rest.len() >= 4 {
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        if rest.len() < 4 + len {
+            break;
+        }
+        strings.push(&rest[4..4 + len]);
+        rest = &rest[4 + len..];
+    }
+    strings
+}
+
+pub struct HostKey {
+    pub key_type: String,
+    blob: Vec<u8>,
+}
+
+impl HostKey {
+    pub fn new(keypair: &KeyPair) -> anyhow::Result<HostKey> {
+        Self::from_public_key(&keypair.clone_public_key()?)
+    }
+
+    pub fn from_public_key(key: &PublicKey) -> anyhow::Result<HostKey> {
+        let blob = key.public_key_bytes();
+        let key_type = match blob_strings(&blob).first() {
+            Some(key_type) => String::from_utf8_lossy(key_type).to_string(),
+            None => anyhow::bail!("Invalid public key blob"),
+        };
+        Ok(HostKey { key_type, blob })
+    }
+
+    /// Short name and size of the key, as shown by ssh-keygen
+    fn label(&self) -> (&str, usize) {
+        match self.key_type.as_str() {
+            "ssh-ed25519" => ("ED25519", 256),
+            "ssh-rsa" => {
+                // the blob is: type, e, n
+                let bits = blob_strings(&self.blob)
+                    .get(2)
+                    .map(|n| {
+                        let n = &n[n.iter().take_while(|byte| **byte == 0).count()..];
+                        n.len() * 8
+                            - n.first()
+                                .map(|byte| byte.leading_zeros() as usize)
+                                .unwrap_or(0)
+                    })
+                    .unwrap_or(0);
+                ("RSA", bits)
+            }
+            "ecdsa-sha2-nistp256" => ("ECDSA", 256),
+            "ecdsa-sha2-nistp384" => ("ECDSA", 384),
+            "ecdsa-sha2-nistp521" => ("ECDSA", 521),
+            other => (other, 0),
+        }
+    }
+
+    pub fn sha256(&self) -> String {
+        format!(
+            "SHA256:{}",
+            BASE64_NOPAD.encode(&Sha256::digest(&self.blob))
+        )
+    }
+
+    pub fn md5(&self) -> String {
+        let hex = HEXLOWER.encode(&Md5::digest(&self.blob));
+        let pairs: Vec<&str> = (0..hex.len()).step_by(2).map(|i| &hex[i..i + 2]).collect();
+        format!("MD5:{}", pairs.join(":"))
+    }
+
+    /// Same drawing as `ssh-keygen -lv` (the "drunken bishop" algorithm), over the SHA256 digest
+    pub fn randomart(&self) -> String {
+        const WIDTH: usize = 17;
+        const HEIGHT: usize = 9;
+        const SYMBOLS: &[u8] = b" .o+=*BOX@%&#/^SE";
+        let start_symbol = SYMBOLS.len() - 2;
+        let end_symbol = SYMBOLS.len() - 1;
+
+        let mut field = [[0usize; HEIGHT]; WIDTH];
+        let (mut x, mut y) = (WIDTH / 2, HEIGHT / 2);
+        for byte in Sha256::digest(&self.blob) {
+            let mut input = byte;
+            for _ in 0..4 {
+                x = if input & 0x1 != 0 {
+                    (x + 1).min(WIDTH - 1)
+                } else {
+                    x.saturating_sub(1)
+                };
+                y = if input & 0x2 != 0 {
+                    (y + 1).min(HEIGHT - 1)
+                } else {
+                    y.saturating_sub(1)
+                };
+                if field[x][y] < start_symbol - 1 {
+                    field[x][y] += 1;
+                }
+                input >>= 2;
+            }
+        }
+        field[WIDTH / 2][HEIGHT / 2] = start_symbol;
+        field[x][y] = end_symbol;
+
+        let border = |title: &str| {
+            let title: String = title.chars().take(WIDTH).collect();
+            let left = (WIDTH - title.len()) / 2;
+            format!(
+                "+{}{title}{}+",
+                "-".repeat(left),
+                "-".repeat(WIDTH - left - title.len())
+            )
+        };
+
+        let (name, bits) = self.label();
+        let mut art = border(&format!("[{name} {bits}]"));
+        art.push('\n');
+        for y in 0..HEIGHT {
+            art.push('|');
+            for column in &field {
+                art.push(SYMBOLS[column[y]] as char);
+            }
+            art.push_str("|\n");
+        }
+        art.push_str(&border("[SHA256]"));
+        art
+    }
+
+    pub fn known_hosts_line(&self, host: &str, port: u16) -> String {
+        let host = if port == 22 {
+            host.to_string()
+        } else {
+            format!("[{host}]:{port}")
+        };
+        format!("{host} {} {}", self.key_type, BASE64.encode(&self.blob))
+    }
+
+    /// SSHFP DNS records (RFC 4255), with SHA-1 and SHA-256 fingerprints
+    pub fn sshfp_records(&self, hostname: &str) -> Vec<String> {
+        let algorithm = match self.key_type.as_str() {
+            "ssh-rsa" => 1,
+            "ssh-dss" => 2,
+            key_type if key_type.starts_with("ecdsa-") => 3,
+            "ssh-ed25519" => 4,
+            _ => return vec![],
+        };
+        vec![
+            format!(
+                "{hostname}. IN SSHFP {algorithm} 1 {}",
+                HEXLOWER.encode(&Sha1::digest(&self.blob))
+            ),
+            format!(
+                "{hostname}. IN SSHFP {algorithm} 2 {}",
+                HEXLOWER.encode(&Sha256::digest(&self.blob))
+            ),
+        ]
+    }
+}
+
+/// Split a listen address into a host usable by clients and a port
+fn client_host_port(addr: &str, hostname: &str) -> Option<(String, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = host.trim_matches(['[', ']']);
+    // wildcard addresses are reachable using the machine name
+    let host = if host.is_empty() || host == "::" || host == "0.0.0.0" {
+        hostname
+    } else {
+        host
+    };
+    Some((host.to_string(), port))
+}
+
+/// Human readable description of the host keys, so clients can verify them
+pub fn report(keys: &[KeyPair], listen: &[String]) -> anyhow::Result<String> {
+    let hostname = crate::utils::get_hostname();
+    let mut out = String::new();
+
+    for keypair in keys {
+        let key = HostKey::new(keypair)?;
+        writeln!(out, "Host key {}", key.key_type)?;
+        writeln!(out, "  {}", key.sha256())?;
+        writeln!(out, "  {}", key.md5())?;
+        for line in key.randomart().lines() {
+            writeln!(out, "  {line}")?;
+        }
+
+        writeln!(out, "  known_hosts:")?;
+        for addr in listen {
+            if let Some((host, port)) = client_host_port(addr, &hostname) {
+                writeln!(out, "    {}", key.known_hosts_line(&host, port))?;
+            }
+        }
+
+        writeln!(out, "  SSHFP:")?;
+        for record in key.sshfp_records(&hostname) {
+            writeln!(out, "    {record}")?;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // expected values come from `ssh-keygen -lv`, `ssh-keygen -l -E md5` and `ssh-keygen -r example.com`
+    const ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBGgjm/wNp99E4rRpdbFl9CCaQ4fc2hmz4+i9hZj18Rc";
+    const RSA: &str = "AAAAB3NzaC1yc2EAAAADAQABAAABAQDC7zKyTnNI7ooYH4K1IiJvt6N/vrxxkh91FtJY6//W2gDFu9AW5IC8pjJQEo+Nb+rUJ68H6i2W9u5YdXAlJP5Yydm8mQq1a9UKEpqb5BJCQq/nzqGP8KM7f0lPbgD8coAO2N8xWSsuETVnFTcawL4Dl3mDf9chpnaYP4UPEa6PFHaOhvwDmbhM5a9hLr1Ra64FcDHAsG4Eupv9TwjHsQOilp63cfpLJNI/E0AqDd86AZLxlPWVTBmImZexh8UF4SfL4HNF1wIAsK/mh4NPnWu2T0Tcw4U/3BBgMoZnsCiHFu5cojz4qiffsozchyeMZqzxGd4r2ReoZ48WrsPJYPqN";
+
+    fn host_key(key: &str) -> HostKey {
+        let base64 = key.split_whitespace().last().unwrap();
+        HostKey::from_public_key(&russh_keys::parse_public_key_base64(base64).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fingerprints() {
+        let key = host_key(ED25519);
+        assert_eq!(key.key_type, "ssh-ed25519");
+        assert_eq!(
+            key.sha256(),
+            "SHA256:MjJb4j70POrWtzqmGXtHafuslQf9nDe1iyFE8WUUG68"
+        );
+        assert_eq!(
+            key.md5(),
+            "MD5:70:9b:1e:53:bd:31:17:10:96:b9:24:b3:99:61:77:0d"
+        );
+        assert_eq!(
+            host_key(RSA).sha256(),
+            "SHA256:A6IkvqM1DGa6y/oQL7RuVRZctYzfdVFJwrL4794ySUU"
+        );
+    }
+
+    #[test]
+    fn randomart_matches_ssh_keygen() {
+        assert_eq!(
+            host_key(ED25519).randomart(),
+            "\
++--[ED25519 256]--+
+|           .  .*.|
+|            o o +|
+|           . . ..|
+|          ..   . |
+|    + + S.... E .|
+|   ..* o+ .o o .o|
+|   .++ o .o...+o.|
+|   .o+O +o .. o.o|
+|   oB*.*o+o  . . |
++----[SHA256]-----+"
+        );
+        assert_eq!(
+            host_key(RSA).randomart(),
+            "\
++---[RSA 2048]----+
+|    . ....  ...o+|
+|     o  o .. ..E.|
+|. . . o. o. o... |
+|.o . + ...... .. |
+|o=. o   S...  .  |
+|==o.     . . .   |
+|++*         o .  |
+|+*..         =.  |
+|B=.         ooo. |
++----[SHA256]-----+"
+        );
+    }
+
+    #[test]
+    fn known_hosts_lines() {
+        let key = host_key(ED25519);
+        assert_eq!(
+            key.known_hosts_line("example.com", 22),
+            format!("example.com {ED25519}")
+        );
+        assert_eq!(
+            key.known_hosts_line("example.com", 2222),
+            format!("[example.com]:2222 {ED25519}")
+        );
+    }
+
+    #[test]
+    fn sshfp_records_match_ssh_keygen() {
+        // ssh-keygen prints relative names, the records use absolute ones
+        assert_eq!(
+            host_key(ED25519).sshfp_records("example.com"),
+            [
+                "example.com. IN SSHFP 4 1 7c701bd795f3c86f5e489a077e07fe68403ee7ca",
+                "example.com. IN SSHFP 4 2 32325be23ef43cead6b73aa6197b4769fbac9507fd9c37b58b2144f165141baf",
+            ]
+        );
+        assert_eq!(
+            host_key(RSA).sshfp_records("example.com"),
+            [
+                "example.com. IN SSHFP 1 1 8c6581f8488ba8f172fa01c967a91dc1f15c81ef",
+                "example.com. IN SSHFP 1 2 03a224bea3350c66bacbfa102fb46e55165cb58cdf755149c2b2f8efde324945",
+            ]
+        );
+    }
+
+    #[test]
+    fn wildcard_addresses_use_the_hostname() {
+        assert_eq!(
+            client_host_port("0.0.0.0:2222", "box"),
+            Some(("box".to_string(), 2222))
+        );
+        assert_eq!(
+            client_host_port("[::]:22", "box"),
+            Some(("box".to_string(), 22))
+        );
+        assert_eq!(
+            client_host_port("[::1]:22", "box"),
+            Some(("::1".to_string(), 22))
+        );
+        assert_eq!(client_host_port("nope", "box"), None);
+    }
+}
@@ -1,35 +1,37 @@
-use std::{env, fs::File};
+use std::{
+    env, fs,
+    io::{ErrorKind, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::Context;
 use log::warn;
-use russh_keys::key::{KeyPair, PublicKey};
+use russh_keys::{
+    key::{KeyPair, PublicKey},
+    PublicKeyBase64,
+};
+use toml::Spanned;
 
 use crate::{
-    cli::Command,
-    ssh::{self, init::Password},
+    cli::{Action, Command, ConfigAction, HostKeyAction, KeysAction, SftpMode},
+    config::{self, Config, Origin},
+    fingerprint::{self, HostKey},
+    host_keys, replay,
+    ssh::{
+        self,
+        control::{self, Request, Response},
+        init::{CredentialsLoader, Password},
+        vfs::{Filesystem, LocalFs, MemoryFs, OverlayFs},
+        Credentials, ServerOptions, User,
+    },
+    utils::{format_duration, parse_duration},
 };
 
-fn init_server_key() -> anyhow::Result<KeyPair> {
-    let xdg = xdg::BaseDirectories::with_prefix("quickssh")?;
-    let existing_prv_key_path = xdg.find_config_file("private.key");
-    if let Some(existing_prv_key_path) = existing_prv_key_path {
-        let keypair = russh_keys::load_secret_key(&existing_prv_key_path, None)?;
-        log::debug!(
-            "Loaded private key from {}",
-            existing_prv_key_path.display()
-        );
-        Ok(keypair)
-    } else {
-        let keypair = KeyPair::generate_ed25519();
-
-        let path = xdg.place_config_file("private.key")?;
-        let f = File::create(&path)?;
-        russh_keys::encode_pkcs8_pem(&keypair, f)?;
-
-        log::info!("Created new ed25519 private key at {}", path.display());
-
-        Ok(keypair)
-    }
-}
+const DEFAULT_PORT: u16 = 2222;
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
 fn parse_key(full_key: &str) -> anyhow::Result<PublicKey> {
     let mut split = full_key.split_whitespace();
@@ -40,14 +42,44 @@ fn parse_key(full_key: &str) -> anyhow::Result<PublicKey> {
     }
 }
 
-fn read_authorized_keys() -> anyhow::Result<Vec<PublicKey>> {
+fn authorized_keys_path() -> anyhow::Result<PathBuf> {
+    let xdg = xdg::BaseDirectories::with_prefix("quickssh")?;
+    Ok(xdg.get_config_home().join("authorized_keys"))
+}
+
+/// Replace the authorized keys through a temporary file, so a running server reloading them never
+/// reads a half written file. The permissions of the existing file are kept
+fn write_authorized_keys(path: &Path, content: &str) -> anyhow::Result<()> {
+    let tmp = path.with_file_name(".authorized_keys.new");
+    let _ = fs::remove_file(&tmp);
+    let mode = fs::metadata(path).map_or(0o600, |metadata| metadata.permissions().mode());
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)
+        .with_context(|| format!("Failed to create {}", tmp.display()))?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
+
+/// When `strict` is set, a line that cannot be parsed is an error instead of being skipped
+fn read_authorized_keys(strict: bool) -> anyhow::Result<Vec<PublicKey>> {
     let xdg = xdg::BaseDirectories::with_prefix("quickssh")?;
     let path = xdg.find_config_file("authorized_keys");
     if let Some(existing_path) = path {
         let mut keys: Vec<PublicKey> = vec![];
         for (i, line) in std::fs::read_to_string(existing_path)?.lines().enumerate() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
             match parse_key(line) {
                 Ok(key) => keys.push(key),
+                Err(err) if strict => {
+                    anyhow::bail!("Failed to parse key from authorized_keys:{} : {}", i, err)
+                }
                 Err(err) => warn!("Failed to parse key from authorized_keys:{} : {}", i, err),
             };
         }
@@ -80,36 +112,731 @@ fn init_logger(verbose: u8) {
     };
 }
 
-pub async fn run(cmd: Command) -> anyhow::Result<()> {
-    init_logger(cmd.verbose);
+fn parse_spanned_key(key: &Spanned<String>, origin: &Origin) -> anyhow::Result<PublicKey> {
+    parse_key(key.get_ref()).map_err(|err| origin.error(key.span(), err))
+}
 
-    let keypair = init_server_key()?;
+fn parse_spanned_duration(
+    value: &Option<Spanned<String>>,
+    origin: &Origin,
+) -> anyhow::Result<Option<Duration>> {
+    value
+        .as_ref()
+        .map(|value| parse_duration(value.get_ref()).map_err(|err| origin.error(value.span(), err)))
+        .transpose()
+}
 
-    let mut pubkeys = read_authorized_keys()?;
-    for (i, key) in cmd.pubkey.iter().enumerate() {
-        match parse_key(key) {
-            Ok(key) => pubkeys.push(key),
-            Err(err) => warn!("Failed to parse key from authorized_keys:{} : {}", i, err),
-        };
+/// Build the user table: the main user (with authorized_keys) followed by the users of the configuration file
+fn resolve_credentials(
+    config: &Config,
+    origin: &Origin,
+    strict: bool,
+) -> anyhow::Result<Credentials> {
+    let mut pubkeys = read_authorized_keys(strict)?;
+    for key in &config.pubkeys {
+        pubkeys.push(parse_spanned_key(key, origin)?);
     }
 
-    let options = ssh::ServerOptions {
-        user: cmd.user.unwrap_or(crate::utils::get_username()?),
-        password: Some(cmd.password.map(Password::Raw).unwrap_or(Password::Su)),
+    let mut users = vec![User {
+        name: match config.user {
+            Some(ref user) => user.clone(),
+            None => crate::utils::get_username()?,
+        },
+        password: Some(
+            config
+                .password
+                .clone()
+                .map(Password::Raw)
+                .unwrap_or(Password::Su),
+        ),
         pubkeys,
-        shell: cmd
-            .shell
-            .unwrap_or_else(|| env::var("SHELL").expect("No SHELL variable defined")),
-        no_shell: cmd.no_shell,
-        no_sftp: cmd.no_sftp,
-        port: cmd.port,
+        sftp_root: config.sftp_root.as_ref().map(PathBuf::from),
+        sftp_mode: config.sftp_mode.unwrap_or_default(),
+    }];
+
+    for user in &config.users {
+        let name = user.name.get_ref();
+        if users.iter().any(|existing| existing.name == *name) {
+            return Err(origin.error(user.name.span(), format!("Duplicate user {name}")));
+        }
+
+        let password = match (&user.password, user.su) {
+            (Some(_), true) => {
+                return Err(origin.error(
+                    user.name.span(),
+                    format!("User {name} cannot have both a password and su = true"),
+                ))
+            }
+            (Some(password), false) => Some(Password::Raw(password.clone())),
+            (None, true) => Some(Password::Su),
+            (None, false) => None,
+        };
+        let pubkeys = user
+            .pubkeys
+            .iter()
+            .map(|key| parse_spanned_key(key, origin))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if password.is_none() && pubkeys.is_empty() {
+            return Err(origin.error(
+                user.name.span(),
+                format!("User {name} has neither a password nor public keys"),
+            ));
+        }
+
+        users.push(User {
+            name: name.clone(),
+            password,
+            pubkeys,
+            sftp_root: user
+                .sftp_root
+                .as_ref()
+                .or(config.sftp_root.as_ref())
+                .map(PathBuf::from),
+            sftp_mode: user.sftp_mode.or(config.sftp_mode).unwrap_or_default(),
+        });
+    }
+
+    Ok(Credentials { users })
+}
+
+fn check_address(addr: &Spanned<String>, origin: &Origin) -> anyhow::Result<()> {
+    let valid = match addr.get_ref().rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
     };
+    if valid {
+        Ok(())
+    } else {
+        Err(origin.error(
+            addr.span(),
+            format!("Invalid address {}, expected host:port", addr.get_ref()),
+        ))
+    }
+}
+
+/// Build what SFTP clients see: the local filesystem, or the directories mounted by the configuration
+fn resolve_sftp_fs(config: &Config, origin: &Origin) -> anyhow::Result<Arc<dyn Filesystem>> {
+    if config.sftp_mounts.is_empty() {
+        return Ok(Arc::new(LocalFs));
+    }
+
+    let mut overlay = OverlayFs::default();
+    for mount in &config.sftp_mounts {
+        let (at, source) = match mount.get_ref().split_once('=') {
+            Some((at, source)) if at.starts_with('/') => (at, source),
+            _ => {
+                return Err(origin.error(
+                    mount.span(),
+                    format!(
+                        "Invalid SFTP mount {}, expected /path=directory or /path=memory:",
+                        mount.get_ref()
+                    ),
+                ))
+            }
+        };
+        if source == "memory:" {
+            overlay.mount(Path::new(at), Arc::new(MemoryFs::default()), Path::new("/"));
+        } else {
+            let source = fs::canonicalize(source).map_err(|err| {
+                origin.error(mount.span(), format!("Cannot mount {source}: {err}"))
+            })?;
+            overlay.mount(Path::new(at), Arc::new(LocalFs), &source);
+        }
+    }
+    Ok(Arc::new(overlay))
+}
+
+fn listen_addresses(config: &Config) -> Vec<String> {
+    if config.listen.is_empty() {
+        vec![format!(":::{}", config.port.unwrap_or(DEFAULT_PORT))]
+    } else {
+        config
+            .listen
+            .iter()
+            .map(|addr| addr.get_ref().clone())
+            .collect()
+    }
+}
 
-    log::info!("Listening on 0.0.0.0:{}", options.port);
-    log::info!("User is {}", options.user);
+fn resolve_options(
+    cmd: &Command,
+    config: &Config,
+    origin: &Origin,
+    credentials: Credentials,
+) -> anyhow::Result<ServerOptions> {
+    for addr in &config.listen {
+        check_address(addr, origin)?;
+    }
+
+    let listen = listen_addresses(config);
+
+    if config.read_only.unwrap_or(false) {
+        // refused rather than ignored, so a read-only server never writes behind the user's back
+        let writers = [
+            (config.control_socket.is_some(), "--control-socket"),
+            (
+                config
+                    .audit_log
+                    .as_deref()
+                    .is_some_and(|target| target != "syslog"),
+                "--audit-log to a file",
+            ),
+            (config.record_dir.is_some(), "--record-dir"),
+        ];
+        if let Some((_, option)) = writers.iter().find(|(set, _)| *set) {
+            anyhow::bail!("{option} writes to the filesystem, it cannot be used with --read-only");
+        }
+    }
+
+    let control_socket = match config.control_socket {
+        Some(ref path) => Some(PathBuf::from(path)),
+        // the socket is a file too, only create it when explicitly asked to
+        None if config.read_only.unwrap_or(false) => None,
+        None => match control::default_path() {
+            Ok(path) => Some(path),
+            Err(err) => {
+                log::warn!("{err}, the sessions and kick subcommands will not work");
+                None
+            }
+        },
+    };
+
+    let watched_files = if config.watch_keys.unwrap_or(false) {
+        let config_path = match origin.path {
+            Some(ref path) => path.clone(),
+            None => config::default_path()?,
+        };
+        vec![authorized_keys_path()?, config_path]
+    } else {
+        vec![]
+    };
+
+    // credentials are reloaded from scratch, with the same command line flags
+    let cmd = cmd.clone();
+    let reload_credentials: CredentialsLoader = Arc::new(move || {
+        let (mut config, origin) = config::load(cmd.config.as_deref())?;
+        config.merge(&cmd);
+        // a broken file must not silently revoke keys of a running server
+        resolve_credentials(&config, &origin, true)
+    });
+
+    Ok(ServerOptions {
+        credentials: Arc::new(RwLock::new(credentials)),
+        reload_credentials,
+        watched_files,
+        shell: match config.shell {
+            Some(ref shell) => shell.clone(),
+            None => env::var("SHELL").context("No SHELL variable defined")?,
+        },
+        no_shell: config.no_shell.unwrap_or(false),
+        no_sftp: config.no_sftp.unwrap_or(false),
+        sftp_fs: resolve_sftp_fs(config, origin)?,
+        listen,
+        inetd: config.inetd.unwrap_or(false),
+        once: config.once.unwrap_or(false),
+        idle_exit: parse_spanned_duration(&config.idle_exit, origin)?,
+        lifetime: parse_spanned_duration(&config.lifetime, origin)?,
+        grace_period: parse_spanned_duration(&config.grace_period, origin)?
+            .unwrap_or(DEFAULT_GRACE_PERIOD),
+        control_socket,
+        control_socket_required: config.control_socket.is_some(),
+        audit_log: config.audit_log.clone(),
+        record_dir: config.record_dir.as_ref().map(PathBuf::from),
+        record_input: config.record_input.unwrap_or(false),
+        detach_grace: parse_spanned_duration(&config.detach_grace, origin)?,
+    })
+}
+
+/// Fill the values left to their default, so they appear in `config dump`
+fn fill_defaults(config: &mut Config, options: &ServerOptions) {
+    let credentials = options.credentials.read().unwrap();
+    config
+        .user
+        .get_or_insert_with(|| credentials.users[0].name.clone());
+    config.shell.get_or_insert_with(|| options.shell.clone());
+    config.ephemeral_host_key.get_or_insert(false);
+    config.read_only.get_or_insert(false);
+    config.watch_keys.get_or_insert(false);
+    config.record_input.get_or_insert(false);
+    config.no_shell.get_or_insert(false);
+    config.no_sftp.get_or_insert(false);
+    config.sftp_mode.get_or_insert(SftpMode::ReadWrite);
+    if config.listen.is_empty() {
+        config.port.get_or_insert(DEFAULT_PORT);
+    }
+    config.inetd.get_or_insert(false);
+    config.once.get_or_insert(false);
+    config
+        .grace_period
+        .get_or_insert_with(|| Spanned::new(0..0, format_duration(options.grace_period)));
+    config.log.verbose.get_or_insert(0);
+}
+
+fn config_command(cmd: &Command, action: &ConfigAction) -> anyhow::Result<()> {
+    let (mut config, origin) = config::load(cmd.config.as_deref())?;
+    match action {
+        ConfigAction::Check => {
+            passphrase_source(&config)?;
+            let credentials = resolve_credentials(&config, &origin, true)?;
+            resolve_options(cmd, &config, &origin, credentials)?;
+            match origin.path {
+                Some(path) => println!("{} is valid", path.display()),
+                None => println!(
+                    "No configuration file at {}, nothing to check",
+                    config::default_path()?.display()
+                ),
+            }
+        }
+        ConfigAction::Dump => {
+            config.merge(cmd);
+            let credentials = resolve_credentials(&config, &origin, false)?;
+            let options = resolve_options(cmd, &config, &origin, credentials)?;
+            fill_defaults(&mut config, &options);
+            config.redact_passwords();
+            print!("{}", toml::to_string_pretty(&config)?);
+        }
+    }
+    Ok(())
+}
+
+fn host_key_paths(config: &Config) -> anyhow::Result<Vec<PathBuf>> {
+    if config.host_keys.is_empty() {
+        return Ok(vec![host_keys::default_path()?]);
+    }
+    Ok(config
+        .host_keys
+        .iter()
+        .map(|path| PathBuf::from(path.get_ref()))
+        .collect())
+}
+
+fn passphrase_source(config: &Config) -> anyhow::Result<Option<PassphraseSource>> {
+    let mut sources: Vec<PassphraseSource> = [
+        config
+            .host_key_passphrase_file
+            .as_ref()
+            .map(|path| PassphraseSource::File(PathBuf::from(path))),
+        config
+            .host_key_passphrase_env
+            .clone()
+            .map(PassphraseSource::Env),
+        config
+            .host_key_passphrase_prompt
+            .unwrap_or(false)
+            .then_some(PassphraseSource::Prompt),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    if sources.len() > 1 {
+        anyhow::bail!("Only one of host_key_passphrase_file, host_key_passphrase_env and host_key_passphrase_prompt can be set");
+    }
+    Ok(sources.pop())
+}
+
+fn init_host_keys(config: &Config) -> anyhow::Result<Vec<KeyPair>> {
+    let ephemeral = config.ephemeral_host_key.unwrap_or(false);
+    let passphrase = match passphrase_source(config)? {
+        Some(source) if !ephemeral => Some(source.read("Host key passphrase: ")?),
+        _ => None,
+    };
+
+    host_keys::init(&HostKeyOptions {
+        paths: host_key_paths(config)?,
+        ephemeral,
+        read_only: config.read_only.unwrap_or(false),
+        passphrase,
+    })
+}
+
+fn ensure_writable(config: &Config) -> anyhow::Result<()> {
+    if config.read_only.unwrap_or(false) {
+        anyhow::bail!("Refusing to modify files in read-only mode");
+    }
+    Ok(())
+}
+
+fn host_key_command(cmd: &Command, action: &HostKeyAction) -> anyhow::Result<()> {
+    let (mut config, _) = config::load(cmd.config.as_deref())?;
+    config.merge(cmd);
+
+    match action {
+        HostKeyAction::Show => {
+            let passphrase = passphrase_source(&config)?
+                .map(|source| source.read("Host key passphrase: "))
+                .transpose()?;
+            for path in host_key_paths(&config)? {
+                if !path.exists() {
+                    anyhow::bail!(
+                        "{} does not exist, it is generated when the server starts",
+                        path.display()
+                    );
+                }
+                let key = host_keys::load(&path, passphrase.as_deref())?.clone_public_key()?;
+                println!(
+                    "{} {} {}",
+                    key.name(),
+                    key.public_key_base64(),
+                    path.display()
+                );
+            }
+        }
+        HostKeyAction::Regenerate { yes } => {
+            ensure_writable(&config)?;
+            if !yes {
+                anyhow::bail!("Clients will get a host key changed warning, run again with --yes to replace the host keys");
+            }
+            let passphrase = passphrase_source(&config)?
+                .map(|source| source.read("Host key passphrase: "))
+                .transpose()?;
+            for path in host_key_paths(&config)? {
+                let keypair = host_keys::regenerate(&path, passphrase.as_deref())?;
+                println!(
+                    "{} is now {}",
+                    path.display(),
+                    HostKey::new(&keypair)?.sha256()
+                );
+            }
+            println!("Restart the server to use the new host keys");
+        }
+        HostKeyAction::Rekey {
+            new_passphrase_file,
+            new_passphrase_env,
+            no_passphrase,
+        } => {
+            ensure_writable(&config)?;
+            let old = passphrase_source(&config)?
+                .map(|source| source.read("Current passphrase: "))
+                .transpose()?;
+            let new = if *no_passphrase {
+                None
+            } else if let Some(path) = new_passphrase_file {
+                Some(PassphraseSource::File(path.clone()).read("")?)
+            } else if let Some(var) = new_passphrase_env {
+                Some(PassphraseSource::Env(var.clone()).read("")?)
+            } else {
+                let new = PassphraseSource::Prompt.read("New passphrase: ")?;
+                if new != PassphraseSource::Prompt.read("Confirm new passphrase: ")? {
+                    anyhow::bail!("Passphrases do not match");
+                }
+                Some(new)
+            };
+            if new.as_deref() == Some("") {
+                anyhow::bail!(
+                    "Empty passphrase, use --no-passphrase to store the host keys unencrypted"
+                );
+            }
+
+            for path in host_key_paths(&config)? {
+                if let Some(format) = host_keys::rekey(&path, old.as_deref(), new.as_deref())? {
+                    eprintln!(
+                        "Warning: {} was in {format} format, it is now in PKCS#8 format. quickssh and OpenSSH read both",
+                        path.display()
+                    );
+                }
+                println!(
+                    "{} is now {}",
+                    path.display(),
+                    if new.is_some() {
+                        "encrypted"
+                    } else {
+                        "unencrypted"
+                    }
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Lines of authorized_keys holding a key, with their index in the file
+fn key_lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+}
+
+fn key_comment(line: &str) -> String {
+    line.split_whitespace()
+        .skip(2)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `selector` can be the number of the key in `keys list`, its fingerprint, its comment or the key itself
+fn key_matches(selector: &str, number: usize, line: &str) -> bool {
+    if selector == number.to_string() || selector == line.trim() || selector == key_comment(line) {
+        return true;
+    }
+    let Ok(key) = parse_key(line) else {
+        return false;
+    };
+    if HostKey::from_public_key(&key).is_ok_and(|key| key.sha256() == selector) {
+        return true;
+    }
+    parse_key(selector).is_ok_and(|selected| selected == key)
+}
+
+fn keys_command(cmd: &Command, action: &KeysAction) -> anyhow::Result<()> {
+    let (mut config, _) = config::load(cmd.config.as_deref())?;
+    config.merge(cmd);
+
+    let path = authorized_keys_path()?;
+    let mut content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    match action {
+        KeysAction::List => {
+            for (number, (_, line)) in key_lines(&content).enumerate() {
+                match parse_key(line).and_then(|key| HostKey::from_public_key(&key)) {
+                    Ok(key) => println!(
+                        "{}: {} {} {}",
+                        number + 1,
+                        key.key_type,
+                        key.sha256(),
+                        key_comment(line)
+                    ),
+                    Err(err) => println!("{}: invalid key ({err})", number + 1),
+                }
+            }
+        }
+        KeysAction::Add { key } => {
+            let line = key.join(" ");
+            let new_key = parse_key(&line)?;
+            let fingerprint = HostKey::from_public_key(&new_key)?.sha256();
+            if key_lines(&content).any(|(_, line)| parse_key(line).is_ok_and(|key| key == new_key))
+            {
+                println!("{fingerprint} is already authorized");
+                return Ok(());
+            }
+
+            ensure_writable(&config)?;
+            if !content.is_empty() && !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(line.trim());
+            content.push('\n');
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_authorized_keys(&path, &content)?;
+            println!("Authorized {fingerprint}");
+        }
+        KeysAction::Remove { key } => {
+            let removed: Vec<usize> = key_lines(&content)
+                .enumerate()
+                .filter(|(number, (_, line))| key_matches(key, number + 1, line))
+                .map(|(_, (index, _))| index)
+                .collect();
+            match removed.len() {
+                0 => anyhow::bail!("No authorized key matches {key}"),
+                1 => {}
+                n => anyhow::bail!(
+                    "{n} authorized keys match {key}, use the key number or fingerprint"
+                ),
+            }
+
+            ensure_writable(&config)?;
+            let new_content: String = content
+                .lines()
+                .enumerate()
+                .filter(|(index, _)| !removed.contains(index))
+                .map(|(_, line)| format!("{line}\n"))
+                .collect();
+            write_authorized_keys(&path, &new_content)?;
+            println!("Removed {key}");
+        }
+    }
+    if !matches!(action, KeysAction::List) && !config.watch_keys.unwrap_or(false) {
+        println!("Send SIGHUP to a running server to apply the change");
+    }
+    Ok(())
+}
+
+fn control_socket_path(config: &Config) -> anyhow::Result<PathBuf> {
+    match config.control_socket {
+        Some(ref path) => Ok(PathBuf::from(path)),
+        None => control::default_path(),
+    }
+}
+
+async fn sessions_command(cmd: &Command) -> anyhow::Result<()> {
+    let (mut config, _) = config::load(cmd.config.as_deref())?;
+    config.merge(cmd);
+
+    let path = control_socket_path(&config)?;
+    let Response::Connections { connections } = control::send(&path, &Request::List).await? else {
+        anyhow::bail!("Unexpected response from the server");
+    };
+    if connections.is_empty() {
+        println!("No connection");
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    println!(
+        "{:<6}{:<16}{:<11}{:<40}{:<12}{:<12}{:<12}CHANNELS",
+        "ID", "USER", "AUTH", "PEER", "CONNECTED", "RECEIVED", "SENT"
+    );
+    for connection in connections {
+        println!(
+            "{:<6}{:<16}{:<11}{:<40}{:<12}{:<12}{:<12}{}",
+            connection.id,
+            connection.user.as_deref().unwrap_or("-"),
+            connection.auth_method.as_deref().unwrap_or("-"),
+            connection.peer.as_deref().unwrap_or("-"),
+            format_duration(Duration::from_secs(now.saturating_sub(connection.started))),
+            connection.bytes_received,
+            connection.bytes_sent,
+            connection.channels.join(", ")
+        );
+    }
+
+    let Response::Shells { shells } = control::send(&path, &Request::Shells).await? else {
+        anyhow::bail!("Unexpected response from the server");
+    };
+    if !shells.is_empty() {
+        println!();
+        println!("{:<7}{:<16}{:<10}VIEWERS", "SHELL", "USER", "PID");
+        for shell in shells {
+            let viewers: Vec<String> = shell
+                .viewers
+                .iter()
+                .map(|(connection, read_only)| {
+                    if *read_only {
+                        format!("{connection} (read-only)")
+                    } else {
+                        connection.to_string()
+                    }
+                })
+                .collect();
+            println!(
+                "{:<7}{:<16}{:<10}{}",
+                shell.id,
+                shell.user,
+                shell.pid.map_or("-".to_string(), |pid| pid.to_string()),
+                viewers.join(", ")
+            );
+        }
+        println!();
+        println!("Log in as <user>:session:<shell> to attach to a shell, or <user>:session:<shell>:ro to watch it");
+        println!("Sending QUICKSSH_SESSION=<shell>[:ro] (ssh -o SetEnv=...) does the same");
+    }
+    Ok(())
+}
+
+async fn kick_command(cmd: &Command, id: usize, reason: Option<String>) -> anyhow::Result<()> {
+    let (mut config, _) = config::load(cmd.config.as_deref())?;
+    config.merge(cmd);
+
+    let path = control_socket_path(&config)?;
+    control::send(&path, &Request::Disconnect { id, reason }).await?;
+    println!("Disconnected connection {id}");
+    Ok(())
+}
+
+async fn broadcast_command(cmd: &Command, message: String) -> anyhow::Result<()> {
+    let (mut config, _) = config::load(cmd.config.as_deref())?;
+    config.merge(cmd);
+
+    let path = control_socket_path(&config)?;
+    if let Response::Broadcasted { sessions } =
+        control::send(&path, &Request::Broadcast { message }).await?
+    {
+        println!("Message sent to {sessions} session(s)");
+    }
+    Ok(())
+}
+
+/// Describe the host keys of the server. Unlike starting it, this never generates keys
+fn fingerprint_command(cmd: &Command) -> anyhow::Result<()> {
+    let (mut config, origin) = config::load(cmd.config.as_deref())?;
+    config.merge(cmd);
+    for addr in &config.listen {
+        check_address(addr, &origin)?;
+    }
+    if config.ephemeral_host_key.unwrap_or(false) {
+        anyhow::bail!("The host key is ephemeral, it is only known once the server has started");
+    }
+
+    let passphrase = passphrase_source(&config)?
+        .map(|source| source.read("Host key passphrase: "))
+        .transpose()?;
+    let keys = host_key_paths(&config)?
+        .iter()
+        .map(|path| {
+            if !path.exists() {
+                anyhow::bail!(
+                    "{} does not exist, it is generated when the server starts",
+                    path.display()
+                );
+            }
+            host_keys::load(path, passphrase.as_deref())
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let listen = listen_addresses(&config);
+    print!("{}", fingerprint::report(&keys, &listen)?);
+    Ok(())
+}
+
+pub async fn run(cmd: Command) -> anyhow::Result<()> {
+    match cmd.action {
+        Some(Action::Config(ref action)) => return config_command(&cmd, action),
+        Some(Action::Fingerprint) => return fingerprint_command(&cmd),
+        Some(Action::HostKey(ref action)) => return host_key_command(&cmd, action),
+        Some(Action::Keys(ref action)) => return keys_command(&cmd, action),
+        Some(Action::Check) => return config_command(&cmd, &ConfigAction::Check),
+        Some(Action::Sessions) => return sessions_command(&cmd).await,
+        Some(Action::Kick { id, ref reason }) => {
+            return kick_command(&cmd, id, reason.clone()).await
+        }
+        Some(Action::Broadcast { ref message }) => {
+            return broadcast_command(&cmd, message.join(" ")).await
+        }
+        Some(Action::Replay {
+            ref file,
+            speed,
+            idle_limit,
+        }) => return replay::play(file, speed, idle_limit).await,
+        Some(Action::Serve) | None => {}
+    }
+
+    let (mut config, origin) = config::load(cmd.config.as_deref())?;
+    config.merge(&cmd);
+
+    init_logger(config.log.verbose.unwrap_or(0));
+
+    let keys = init_host_keys(&config)?;
+
+    let credentials = resolve_credentials(&config, &origin, false)?;
+    let options = resolve_options(&cmd, &config, &origin, credentials.clone())?;
+
+    if let Some(ref path) = origin.path {
+        log::info!("Loaded configuration from {}", path.display());
+    }
+    if config.read_only.unwrap_or(false) {
+        log::info!("Read-only mode: quickssh will not write to the filesystem");
+    }
+    if options.inetd {
+        log::info!("Serving a single connection over stdin/stdout");
+    } else {
+        for addr in &options.listen {
+            log::info!("Listening on {addr}");
+        }
+    }
+    let main_user = &credentials.users[0];
+    for key in &keys {
+        log::info!("Using {} host key", key.name());
+    }
+    log::info!("User is {}", main_user.name);
     log::info!(
         "Password is {}",
-        match options.password {
+        match main_user.password {
             Some(Password::Raw(ref password)) => password,
             Some(Password::Su) => "checked using su",
             None => "unset",
@@ -119,10 +846,47 @@ pub async fn run(cmd: Command) -> anyhow::Result<()> {
           //     "checked using `su`"
           // }
     );
-    log::info!("{} public key(s) loaded", options.pubkeys.len());
+    log::info!("{} public key(s) loaded", main_user.pubkeys.len());
+    if let Some(ref root) = main_user.sftp_root {
+        log::info!("SFTP is confined to {}", root.display());
+    }
+    match main_user.sftp_mode {
+        SftpMode::ReadWrite => {}
+        SftpMode::ReadOnly => log::info!("SFTP is read-only"),
+        SftpMode::DropBox => log::info!("SFTP is upload-only (drop box)"),
+    }
+    for user in &credentials.users[1..] {
+        log::info!(
+            "Additional user {} ({} public key(s))",
+            user.name,
+            user.pubkeys.len()
+        );
+    }
+    if let Some(ref audit_log) = options.audit_log {
+        log::info!("Writing the audit log to {audit_log}");
+    }
+    if let Some(ref dir) = options.record_dir {
+        log::info!("Recording shell sessions to {}", dir.display());
+    }
+    if let Some(detach_grace) = options.detach_grace {
+        log::info!("Detached shells are kept for {}s", detach_grace.as_secs());
+    }
+    if options.once {
+        log::info!("Will exit after the first connection");
+    }
+    if let Some(idle_exit) = options.idle_exit {
+        log::info!("Will exit after {}s without sessions", idle_exit.as_secs());
+    }
+    if let Some(lifetime) = options.lifetime {
+        log::info!("Will exit after {}s", lifetime.as_secs());
+    }
 
-    println!();
+    // stdout carries the SSH stream in inetd mode
+    if !options.inetd {
+        println!();
+        println!("{}", fingerprint::report(&keys, &options.listen)?);
+    }
 
-    ssh::start_ssh_server(options, keypair).await?;
+    ssh::start_ssh_server(options, host_keys::server_keys(keys)).await?;
     Ok(())
 }
@@ -1,7 +1,11 @@
 use clap::Parser;
 
 pub mod cli;
+pub mod config;
+pub mod fingerprint;
+pub mod host_keys;
 pub mod logic;
+pub mod replay;
 pub mod ssh;
 pub mod utils;
 
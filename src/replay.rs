@@ -0,0 +1,54 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde_json::Value;
+
+/// Play an asciicast v2 recording on stdout, `speed` times faster than it was recorded
+pub async fn play(path: &Path, speed: f64, idle_limit: Option<f64>) -> anyhow::Result<()> {
+    if !(speed.is_finite() && speed > 0.0) {
+        anyhow::bail!("The speed must be a positive number");
+    }
+    if let Some(limit) = idle_limit.filter(|limit| !(limit.is_finite() && *limit > 0.0)) {
+        anyhow::bail!("The idle limit must be a positive number of seconds, not {limit}");
+    }
+
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut lines = content.lines();
+
+    let header: Value = serde_json::from_str(lines.next().context("Empty recording")?)?;
+    if header["version"] != 2 {
+        anyhow::bail!("{} is not an asciicast v2 recording", path.display());
+    }
+
+    let mut stdout = io::stdout();
+    let mut previous = 0.0;
+    for (i, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: (f64, String, String) = serde_json::from_str(line)
+            .with_context(|| format!("Invalid event on line {}", i + 2))?;
+        let (time, code, data) = event;
+
+        let mut delay = (time - previous).max(0.0);
+        if let Some(limit) = idle_limit {
+            delay = delay.min(limit);
+        }
+        previous = time;
+        let delay = Duration::try_from_secs_f64(delay / speed)
+            .with_context(|| format!("Invalid time on line {}", i + 2))?;
+        tokio::time::sleep(delay).await;
+
+        // input is already echoed in the output, and resizing the user's terminal would be rude
+        if code == "o" {
+            stdout.write_all(data.as_bytes())?;
+            stdout.flush()?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+/// Something worth keeping a trace of. Never holds secrets (passwords, private keys)
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Connect {
+        peer: Option<String>,
+    },
+    Disconnect {
+        user: Option<&'a str>,
+        /// Seconds since the connection was opened
+        duration: u64,
+        bytes_received: u64,
+        bytes_sent: u64,
+    },
+    Auth {
+        user: &'a str,
+        method: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<String>,
+        success: bool,
+    },
+    ChannelOpen {
+        kind: &'a str,
+        allowed: bool,
+    },
+    Exec {
+        command: &'a str,
+    },
+    Subsystem {
+        name: &'a str,
+    },
+    /// Attached to a running shell
+    Attach {
+        shell: usize,
+        read_only: bool,
+    },
+    SftpOpen {
+        path: &'a str,
+        flags: String,
+        success: bool,
+    },
+    /// Emitted when a file opened for writing is closed
+    SftpWrite {
+        path: &'a str,
+        bytes: u64,
+    },
+    SftpRename {
+        from: &'a str,
+        to: &'a str,
+        success: bool,
+    },
+    SftpRemove {
+        path: &'a str,
+        success: bool,
+    },
+    SftpMkdir {
+        path: &'a str,
+        success: bool,
+    },
+    SftpRmdir {
+        path: &'a str,
+        success: bool,
+    },
+    SftpSetstat {
+        path: &'a str,
+        attrs: String,
+        success: bool,
+    },
+    SftpSymlink {
+        link: &'a str,
+        target: &'a str,
+        success: bool,
+    },
+    SftpHardlink {
+        link: &'a str,
+        target: &'a str,
+        success: bool,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    /// Unix timestamp, in seconds
+    time: f64,
+    connection: usize,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+enum Sink {
+    File(Mutex<File>),
+    Syslog,
+}
+
+/// Writes audit events as JSON lines, to a file or to syslog. Does nothing when no target is configured
+#[derive(Clone, Default)]
+pub struct Audit {
+    sink: Option<Arc<Sink>>,
+}
+
+impl Audit {
+    /// `target` is a file path, or `syslog`
+    pub fn open(target: Option<&str>) -> anyhow::Result<Audit> {
+        let sink = match target {
+            None => return Ok(Audit::default()),
+            Some("syslog") => {
+                unsafe {
+                    libc::openlog(
+                        b"quickssh\0".as_ptr() as *const libc::c_char,
+                        libc::LOG_PID,
+                        libc::LOG_AUTHPRIV,
+                    );
+                }
+                Sink::Syslog
+            }
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(path)
+                    .with_context(|| format!("Failed to open audit log {path}"))?;
+                Sink::File(Mutex::new(file))
+            }
+        };
+        Ok(Audit {
+            sink: Some(Arc::new(sink)),
+        })
+    }
+
+    pub fn log(&self, connection: usize, event: Event) {
+        let Some(ref sink) = self.sink else {
+            return;
+        };
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let line = match serde_json::to_string(&Record {
+            time,
+            connection,
+            event: &event,
+        }) {
+            Ok(line) => line,
+            Err(err) => {
+                log::error!("Failed to serialize audit event {event:?}: {err}");
+                return;
+            }
+        };
+
+        match sink.as_ref() {
+            Sink::File(file) => {
+                if let Err(err) = writeln!(file.lock().unwrap(), "{line}") {
+                    log::error!("Failed to write to the audit log: {err}");
+                }
+            }
+            Sink::Syslog => {
+                // serde_json escapes control characters, so the line has no NUL byte
+                if let Ok(line) = CString::new(line) {
+                    unsafe {
+                        libc::syslog(
+                            libc::LOG_AUTHPRIV | libc::LOG_INFO,
+                            b"%s\0".as_ptr() as *const libc::c_char,
+                            line.as_ptr(),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    fs::{self, DirBuilder},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use super::{
+    registry::{ConnectionInfo, Registry},
+    shells::{ShellInfo, Shells},
+};
+
+/// A request sent to the control socket, as one JSON object per line
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    List,
+    /// Running shells, that can be attached to by logging in as `user:session:<id>`
+    Shells,
+    Disconnect {
+        id: usize,
+        reason: Option<String>,
+    },
+    Broadcast {
+        message: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Connections { connections: Vec<ConnectionInfo> },
+    Shells { shells: Vec<ShellInfo> },
+    Broadcasted { sessions: usize },
+    Error { message: String },
+}
+
+pub fn default_path() -> anyhow::Result<PathBuf> {
+    let xdg = xdg::BaseDirectories::with_prefix("quickssh")?;
+    let runtime_dir = xdg
+        .get_runtime_directory()
+        .context("No control socket path, set XDG_RUNTIME_DIR or use --control-socket")?;
+    Ok(runtime_dir.join("quickssh").join("control.sock"))
+}
+
+/// Removes the socket file when the server stops
+pub struct SocketGuard {
+    path: PathBuf,
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Listen on the control socket, answering requests in the background
+pub async fn listen(
+    path: &Path,
+    registry: Registry,
+    shells: Shells,
+) -> anyhow::Result<SocketGuard> {
+    if let Some(parent) = path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            anyhow::bail!(
+                "Another server is already listening on {}, use --control-socket to choose another path",
+                path.display()
+            );
+        }
+        // left over by a server that did not exit cleanly
+        fs::remove_file(path)?;
+    }
+
+    // created with 0600 right away, other users must never get a window to connect
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe {
+        libc::umask(umask);
+    }
+    let listener = listener.with_context(|| format!("Failed to listen on {}", path.display()))?;
+    log::debug!("Control socket listening on {}", path.display());
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_client(stream, registry.clone(), shells.clone()));
+                }
+                Err(err) => log::error!("Failed to accept control connection: {err}"),
+            }
+        }
+    });
+
+    Ok(SocketGuard {
+        path: path.to_path_buf(),
+    })
+}
+
+async fn serve_client(stream: UnixStream, registry: Registry, shells: Shells) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle(request, &registry, &shells).await,
+            Err(err) => Response::Error {
+                message: format!("Invalid request: {err}"),
+            },
+        };
+
+        let mut out = match serde_json::to_string(&response) {
+            Ok(out) => out,
+            Err(err) => {
+                log::error!("Failed to serialize control response: {err}");
+                return;
+            }
+        };
+        out.push('\n');
+        if writer.write_all(out.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn handle(request: Request, registry: &Registry, shells: &Shells) -> Response {
+    log::debug!("Control request: {request:?}");
+    match request {
+        Request::List => Response::Connections {
+            connections: registry.list(),
+        },
+        Request::Shells => Response::Shells {
+            shells: shells.list(),
+        },
+        Request::Disconnect { id, reason } => {
+            let reason = reason.unwrap_or_else(|| "Disconnected by the administrator".to_string());
+            match registry.disconnect(id, &reason).await {
+                Ok(()) => {
+                    log::info!("Disconnected connection {id}: {reason}");
+                    Response::Ok
+                }
+                Err(err) => Response::Error {
+                    message: err.to_string(),
+                },
+            }
+        }
+        Request::Broadcast { message } => {
+            let sessions = registry
+                .broadcast(&format!("\r\n*** {message} ***\r\n"))
+                .await;
+            log::info!("Broadcasted to {sessions} session(s): {message}");
+            Response::Broadcasted { sessions }
+        }
+    }
+}
+
+/// Send a request to a running server
+pub async fn send(path: &Path, request: &Request) -> anyhow::Result<Response> {
+    let stream = UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "Failed to connect to {}, is the server running?",
+            path.display()
+        )
+    })?;
+    let (reader, mut writer) = stream.into_split();
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await?;
+
+    let response = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .context("The server closed the control connection")?;
+    match serde_json::from_str(&response)? {
+        Response::Error { message } => anyhow::bail!(message),
+        response => Ok(response),
+    }
+}
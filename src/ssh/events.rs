@@ -1,14 +1,22 @@
 /// inspired from https://github.com/brandonros/rustbear/blob/master/src/main.rs
+use std::sync::Arc;
+
+use anyhow::Context;
 use async_trait::async_trait;
 use log::info;
 use russh::server::{Auth, Msg, Session};
 use russh::*;
 use russh_keys::*;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncReadExt;
 
-use super::init::Password;
+use super::audit::Event;
+use super::init::{AttachRequest, Password, PtyInfo, User, SESSION_ENV};
+use super::metered::Metered;
+use super::recording::{Recorder, RecordingHeader};
+use super::shells::{SharedShell, Viewer};
 use super::su_login::su_login;
 use super::Server;
+use crate::fingerprint::HostKey;
 
 impl server::Server for Server {
     type Handler = Self;
@@ -16,8 +24,9 @@ impl server::Server for Server {
         if let Some(addr) = addr {
             log::info!("new client from {}", addr);
         }
-        let s = self.clone();
-        self.id += 1;
+        let mut s = self.clone();
+        s.id = self.registry.next_id();
+        s.connection = Some(Arc::new(self.registry.register(s.id, addr)));
         s
     }
 }
@@ -33,6 +42,14 @@ impl server::Handler for Server {
     ) -> Result<(Self, bool, Session), Self::Error> {
         {
             log::debug!("channel_open_session");
+            self.registry.set_channel(self.id, channel.id(), "session");
+            self.registry.audit().log(
+                self.id,
+                Event::ChannelOpen {
+                    kind: "session",
+                    allowed: true,
+                },
+            );
             let mut clients = self.clients.lock().await;
             clients.insert((self.id, channel.id()), channel);
         }
@@ -49,9 +66,75 @@ impl server::Handler for Server {
 
         log::debug!("shell_request channel_id = {channel_id}");
 
+        let user = self.registry.user(self.id).unwrap_or_default();
+        let info = self
+            .ptys
+            .lock()
+            .await
+            .remove(&(self.id, channel_id))
+            .unwrap_or_else(|| PtyInfo {
+                term: "xterm".to_string(),
+                cols: 80,
+                rows: 24,
+            });
+        let mut viewer = Viewer {
+            connection: self.id,
+            channel: channel_id,
+            handle: session.handle(),
+            read_only: false,
+            cols: info.cols,
+            rows: info.rows,
+            traffic: self.traffic(),
+        };
+
+        if let Some(ref attach) = self.attach {
+            let shell = self
+                .shells
+                .get(attach.shell)
+                .filter(|shell| shell.user == user)
+                .with_context(|| format!("No shell {} for user {user}", attach.shell))?;
+            viewer.read_only = attach.read_only;
+            self.shells.attach(&shell, viewer).await;
+
+            log::info!(
+                "{user} attached to shell {}{}",
+                shell.id,
+                if attach.read_only { " (read-only)" } else { "" }
+            );
+            self.registry.audit().log(
+                self.id,
+                Event::Attach {
+                    shell: shell.id,
+                    read_only: attach.read_only,
+                },
+            );
+            self.registry.add_shell(self.id, channel_id, None);
+            self.registry
+                .set_channel(self.id, channel_id, &format!("shell {}", shell.id));
+            session.request_success();
+            return Ok((self, session));
+        }
+
+        let recorder = match self.options.record_dir {
+            Some(ref dir) => {
+                let header = RecordingHeader {
+                    connection: self.id,
+                    channel: channel_id,
+                    user: &user,
+                    term: &info.term,
+                    shell: &self.options.shell,
+                    cols: info.cols,
+                    rows: info.rows,
+                };
+                // sessions that cannot be recorded are refused
+                Some(Recorder::create(dir, &header, self.options.record_input)?)
+            }
+            None => None,
+        };
+
         // create pty
         let pty = pty_process::Pty::new().unwrap();
-        if let Err(e) = pty.resize(pty_process::Size::new(24, 80)) {
+        if let Err(e) = pty.resize(pty_process::Size::new(info.rows as u16, info.cols as u16)) {
             log::error!("pty.resize failed: {:?}", e);
         }
 
@@ -61,43 +144,63 @@ impl server::Handler for Server {
         // split pty into reader + writer
         let (mut pty_reader, pty_writer) = pty.into_split();
 
-        // insert pty_reader
-        self.channel_pty_writers
-            .lock()
-            .await
-            .insert(channel_id, pty_writer);
+        // Spawn a new shell process in pty
+        let shell_id = self.shells.next_id();
+        let mut child = pty_process::Command::new(&self.options.shell)
+            .env("TERM", &info.term)
+            .env(SESSION_ENV, shell_id.to_string())
+            .spawn(&pts)
+            .map_err(anyhow::Error::new)?;
 
-        // pty_reader.read() -> session_handle.data()
-        let session_handle = session.handle().clone();
+        let shell = Arc::new(SharedShell::new(
+            shell_id,
+            user,
+            child.id(),
+            pty_writer,
+            recorder,
+        ));
+        self.shells.insert(shell.clone());
+        self.shells.attach(&shell, viewer).await;
+
+        self.registry.audit().log(
+            self.id,
+            Event::Exec {
+                command: &self.options.shell,
+            },
+        );
+        self.registry.add_shell(self.id, channel_id, child.id());
+        self.registry
+            .set_channel(self.id, channel_id, &format!("shell {}", shell.id));
+
+        // pty_reader.read() -> every attached channel
+        let reader_shell = shell.clone();
+        let detach_grace = self.shells.detach_grace();
         tokio::spawn(async move {
             let mut buffer = vec![0; 1024];
             while let Ok(size) = pty_reader.read(&mut buffer).await {
                 if size == 0 {
                     log::debug!("pty_reader read 0");
-                    // TODO: kill pty + command?
-                    let _ = session_handle.close(channel_id).await;
                     break;
                 }
-                let _ = session_handle
-                    .data(channel_id, CryptoVec::from_slice(&buffer[0..size]))
-                    .await;
+                if reader_shell.output(&buffer[0..size]).await {
+                    reader_shell.release(detach_grace);
+                }
             }
         });
 
-        // Spawn a new shell process in pty
-        let mut child = pty_process::Command::new(&self.options.shell)
-            .spawn(&pts)
-            .map_err(anyhow::Error::new)?;
-
-        // close session when process exits?
-        let session_handle = session.handle().clone();
+        // close the attached channels when the process exits
+        let registry = self.registry.clone();
+        let shells = self.shells.clone();
         tokio::spawn(async move {
             let exit_status = child.wait().await.unwrap();
-            session_handle
-                .exit_status_request(channel_id, exit_status.code().unwrap_or(1) as u32)
-                .await
-                .unwrap();
-            let _ = session_handle.close(channel_id).await;
+            shells.remove(shell.id);
+            for (connection, channel, handle) in shell.viewers() {
+                registry.remove_shell(connection, channel);
+                let _ = handle
+                    .exit_status_request(channel, exit_status.code().unwrap_or(1) as u32)
+                    .await;
+                let _ = handle.close(channel).await;
+            }
         });
 
         // mark request success
@@ -116,29 +219,85 @@ impl server::Handler for Server {
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
         log::debug!("window_change_request channel_id = {channel_id:?} col_width = {col_width} row_height = {row_height}");
-        let mut channel_pty_writers = self.channel_pty_writers.lock().await;
-        if let Some(pty_writer) = channel_pty_writers.get_mut(&channel_id) {
-            if let Err(e) =
-                pty_writer.resize(pty_process::Size::new(row_height as u16, col_width as u16))
-            {
-                log::error!("pty.resize failed: {:?}", e);
-            }
+        if let Some(shell) = self.shells.find(self.id, channel_id) {
+            shell
+                .resize(self.id, channel_id, col_width, row_height)
+                .await;
         }
-        drop(channel_pty_writers);
         Ok((self, session))
     }
 
-    async fn auth_publickey(
+    async fn env_request(
+        mut self,
+        channel_id: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        log::debug!("env_request channel_id = {channel_id} {variable_name}={variable_value}");
+        // other variables are ignored, the shell gets the environment of the server
+        if variable_name == SESSION_ENV {
+            self.attach = AttachRequest::parse(variable_value);
+        }
+        Ok((self, session))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn pty_request(
         self,
-        user: &str,
+        channel_id: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+        _modes: &[(Pty, u32)],
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        log::debug!("pty_request channel_id = {channel_id} term = {term} col_width = {col_width} row_height = {row_height}");
+        self.ptys.lock().await.insert(
+            (self.id, channel_id),
+            PtyInfo {
+                term: term.to_string(),
+                cols: col_width,
+                rows: row_height,
+            },
+        );
+        session.channel_success(channel_id);
+        Ok((self, session))
+    }
+
+    async fn auth_publickey(
+        mut self,
+        login: &str,
         public_key: &russh_keys::key::PublicKey,
     ) -> Result<(Self, Auth), Self::Error> {
+        let (user, attach) = AttachRequest::parse_login(login);
         log::info!(
             "auth_publickey: user: {user} public_key: {}",
             public_key.public_key_base64()
         );
-        let public_key_is_valid = self.options.pubkeys.contains(public_key);
+        let public_key_is_valid = self
+            .options
+            .credentials
+            .read()
+            .unwrap()
+            .user(user)
+            .is_some_and(|user| user.pubkeys.contains(public_key));
+        self.registry.audit().log(
+            self.id,
+            Event::Auth {
+                user,
+                method: "publickey",
+                fingerprint: HostKey::from_public_key(public_key)
+                    .ok()
+                    .map(|key| key.sha256()),
+                success: public_key_is_valid,
+            },
+        );
         if public_key_is_valid {
+            self.registry.set_user(self.id, user, "publickey");
+            self.attach = attach;
             Ok((self, server::Auth::Accept))
         } else {
             Ok((
@@ -160,23 +319,42 @@ impl server::Handler for Server {
         ))
     }
 
-    async fn auth_password(self, user: &str, password: &str) -> Result<(Self, Auth), Self::Error> {
-        // if the user wants to authenticate using actual system credentials, let's assume they don't want them logged
-        if matches!(self.options.password, Some(Password::Su)) {
-            log::info!("auth_password: credentials: {}, [HIDDEN]", user);
-        } else {
-            log::info!("auth_password: credentials: {}, {}", user, password);
-        }
+    async fn auth_password(
+        mut self,
+        login: &str,
+        password: &str,
+    ) -> Result<(Self, Auth), Self::Error> {
+        let (user, attach) = AttachRequest::parse_login(login);
+        let entry = self.options.credentials.read().unwrap().user(user).cloned();
 
-        if user == self.options.user {
-            let result = match self.options.password {
-                Some(Password::Raw(ref right_password)) => right_password == password,
-                Some(Password::Su) => su_login(&self.options.user, password).unwrap(),
-                None => false,
-            };
-            if result {
-                return Ok((self, Auth::Accept));
-            }
+        // passwords are never logged: someone trying the wrong user may be typing a real credential
+        log::info!("auth_password: user: {user}");
+
+        let result = match entry {
+            Some(User {
+                password: Some(Password::Raw(ref right_password)),
+                ..
+            }) => right_password == password,
+            Some(User {
+                ref name,
+                password: Some(Password::Su),
+                ..
+            }) => su_login(name, password).unwrap(),
+            _ => false,
+        };
+        self.registry.audit().log(
+            self.id,
+            Event::Auth {
+                user,
+                method: "password",
+                fingerprint: None,
+                success: result,
+            },
+        );
+        if result {
+            self.registry.set_user(self.id, user, "password");
+            self.attach = attach;
+            return Ok((self, Auth::Accept));
         }
 
         Ok((
@@ -187,12 +365,21 @@ impl server::Handler for Server {
         ))
     }
 
+    async fn auth_succeeded(self, session: Session) -> Result<(Self, Session), Self::Error> {
+        self.registry.set_handle(self.id, session.handle());
+        Ok((self, session))
+    }
+
     async fn channel_close(
         self,
         channel_id: ChannelId,
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
         log::debug!("channel_close channel_id = {channel_id:?}");
+        self.registry.remove_channel(self.id, channel_id);
+        self.registry.remove_shell(self.id, channel_id);
+        self.ptys.lock().await.remove(&(self.id, channel_id));
+        self.shells.detach(self.id, channel_id).await;
         // TODO: cleanup
         Ok((self, session))
     }
@@ -203,14 +390,14 @@ impl server::Handler for Server {
         data: &[u8],
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        let mut channel_pty_writers = self.channel_pty_writers.lock().await;
-        if let Some(pty_writer) = channel_pty_writers.get_mut(&channel_id) {
-            pty_writer
-                .write_all(data)
-                .await
-                .map_err(anyhow::Error::new)?;
+        if let Some(traffic) = self.traffic() {
+            traffic.add_received(data.len());
+        }
+        if let Some(shell) = self.shells.find(self.id, channel_id) {
+            if !shell.is_read_only(self.id, channel_id) {
+                shell.input(data).await.map_err(anyhow::Error::new)?;
+            }
         }
-        drop(channel_pty_writers);
         Ok((self, session))
     }
 
@@ -221,22 +408,50 @@ impl server::Handler for Server {
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
         info!("subsystem: {}", name);
+        self.registry
+            .audit()
+            .log(self.id, Event::Subsystem { name });
 
-        use super::sftp_events::SftpSession;
+        use super::sftp_root::SftpRoot;
+        use super::{sftp_dispatch, sftp_events::SftpSession};
 
         if name == "sftp" {
             if self.options.no_sftp {
                 anyhow::bail!("SFTP access disabled");
             }
 
+            // a user removed or renamed by a credentials reload gets no SFTP at all, never the
+            // unconfined read-write defaults
+            let access = self.registry.user(self.id).and_then(|user| {
+                let credentials = self.options.credentials.read().unwrap();
+                credentials
+                    .user(&user)
+                    .map(|user| (user.sftp_root.clone(), user.sftp_mode))
+            });
+            let Some((root, mode)) = access else {
+                log::warn!(
+                    "Refused SFTP for connection {}: its user is not configured anymore",
+                    self.id
+                );
+                session.channel_failure(channel_id);
+                return Ok((self, session));
+            };
+
             let channel = {
                 let mut clients = self.clients.lock().await;
                 clients.remove(&(self.id, channel_id)).unwrap()
             };
+            let fs = self.options.sftp_fs.clone();
+            let root = match root {
+                Some(ref path) => SftpRoot::new(fs.clone(), Some(path))
+                    .with_context(|| format!("Failed to open the SFTP root {}", path.display()))?,
+                None => SftpRoot::new(fs.clone(), None)?,
+            };
 
-            let sftp = SftpSession::default();
+            let sftp = SftpSession::new(fs, root, mode, self.registry.audit().clone(), self.id);
+            self.registry.set_channel(self.id, channel_id, "sftp");
             session.channel_success(channel_id);
-            russh_sftp::server::run(channel.into_stream(), sftp).await;
+            sftp_dispatch::run(Metered::new(channel.into_stream(), self.traffic()), sftp);
         } else {
             session.channel_failure(channel_id);
         }
@@ -1,17 +1,89 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use pty_process::OwnedWritePty;
 use russh::{server::Msg, Channel, ChannelId, MethodSet};
 use russh_keys::key::{self, KeyPair};
 use tokio::sync::Mutex;
 
+use crate::cli::SftpMode;
+
+use super::{
+    audit::Audit,
+    control, lifecycle,
+    registry::{ConnectionGuard, Registry, Traffic},
+    reload,
+    shells::Shells,
+    stdio::Stdio,
+    vfs::Filesystem,
+};
+
 #[derive(Clone)]
 pub struct Server {
     #[allow(clippy::type_complexity)]
     pub clients: Arc<Mutex<HashMap<(usize, ChannelId), Channel<Msg>>>>,
-    pub channel_pty_writers: Arc<Mutex<HashMap<ChannelId, OwnedWritePty>>>,
+    pub ptys: Arc<Mutex<HashMap<(usize, ChannelId), PtyInfo>>>,
+    pub shells: Shells,
+    // shell to attach to instead of starting one, asked for when logging in
+    pub attach: Option<AttachRequest>,
     pub id: usize,
     pub options: ServerOptions,
+    pub registry: Registry,
+    // set for client handlers only, unregisters the connection once dropped
+    pub connection: Option<Arc<ConnectionGuard>>,
+}
+
+/// Terminal requested by a client for a channel
+pub struct PtyInfo {
+    pub term: String,
+    pub cols: u32,
+    pub rows: u32,
+}
+
+/// Login names like `user:session:3` or `user:session:3:ro` attach to the running shell 3 of `user`.
+/// Setting `QUICKSSH_SESSION=3` (or `3:ro`) in the environment of the channel does the same
+#[derive(Clone, Copy, Debug)]
+pub struct AttachRequest {
+    pub shell: usize,
+    pub read_only: bool,
+}
+
+/// Environment variable holding the id of the shell, to attach to, or that a shell runs in
+pub const SESSION_ENV: &str = "QUICKSSH_SESSION";
+
+impl AttachRequest {
+    /// Parse `3` or `3:ro`
+    pub fn parse(value: &str) -> Option<AttachRequest> {
+        let (shell, read_only) = match value.strip_suffix(":ro") {
+            Some(shell) => (shell, true),
+            None => (value, false),
+        };
+        let shell = shell.parse().ok()?;
+        Some(AttachRequest { shell, read_only })
+    }
+
+    /// Split a login name into the user name and the shell to attach to
+    pub fn parse_login(login: &str) -> (&str, Option<AttachRequest>) {
+        let Some((user, suffix)) = login.split_once(":session:") else {
+            return (login, None);
+        };
+        match AttachRequest::parse(suffix) {
+            Some(attach) => (user, Some(attach)),
+            None => (login, None),
+        }
+    }
+}
+
+impl Server {
+    /// Traffic counters of the connection, for client handlers
+    pub fn traffic(&self) -> Option<Arc<Traffic>> {
+        self.connection
+            .as_ref()
+            .map(|connection| connection.traffic.clone())
+    }
 }
 
 #[derive(Clone)]
@@ -21,42 +93,145 @@ pub enum Password {
 }
 
 #[derive(Clone)]
-pub struct ServerOptions {
-    pub user: String,
+pub struct User {
+    pub name: String,
     pub password: Option<Password>,
     pub pubkeys: Vec<key::PublicKey>,
-    pub shell: String,
-    pub no_shell: bool,
-    pub no_sftp: bool,
-    pub port: u16,
+    // directory SFTP is confined to
+    pub sftp_root: Option<PathBuf>,
+    pub sftp_mode: SftpMode,
 }
 
-pub async fn start_ssh_server(options: ServerOptions, keypair: KeyPair) -> anyhow::Result<()> {
-    let mut methods_list: Vec<MethodSet> = vec![MethodSet::PASSWORD];
-    if !options.pubkeys.is_empty() {
-        methods_list.push(MethodSet::PUBLICKEY);
+/// Who can log in. Can be reloaded while the server is running
+#[derive(Clone)]
+pub struct Credentials {
+    pub users: Vec<User>,
+}
+
+impl Credentials {
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.name == name)
     }
+}
+
+pub type CredentialsLoader = Arc<dyn Fn() -> anyhow::Result<Credentials> + Send + Sync>;
 
-    let methods = methods_list.into_iter().reduce(|a, b| a | b);
+#[derive(Clone)]
+pub struct ServerOptions {
+    pub credentials: Arc<RwLock<Credentials>>,
+    // called on SIGHUP (or when one of `watched_files` changes) to refresh `credentials`
+    pub reload_credentials: CredentialsLoader,
+    pub watched_files: Vec<PathBuf>,
+    pub shell: String,
+    pub no_shell: bool,
+    pub no_sftp: bool,
+    // what SFTP clients see, shared by every session
+    pub sftp_fs: Arc<dyn Filesystem>,
+    pub listen: Vec<String>,
+    pub inetd: bool,
+    pub once: bool,
+    pub idle_exit: Option<Duration>,
+    pub lifetime: Option<Duration>,
+    pub grace_period: Duration,
+    // socket used by the sessions and kick subcommands
+    pub control_socket: Option<PathBuf>,
+    // the socket path was given explicitly, so failing to listen on it is an error
+    pub control_socket_required: bool,
+    // file path or "syslog"
+    pub audit_log: Option<String>,
+    // record interactive shells in this directory
+    pub record_dir: Option<PathBuf>,
+    pub record_input: bool,
+    // keep shells of dropped connections for this long, so they can be reattached
+    pub detach_grace: Option<Duration>,
+}
 
+pub async fn start_ssh_server(options: ServerOptions, keys: Vec<KeyPair>) -> anyhow::Result<()> {
     let config = russh::server::Config {
-        methods: methods.expect("At least one authentication method should be available"),
+        // public keys are always accepted, since they may be added by a reload
+        methods: MethodSet::PASSWORD | MethodSet::PUBLICKEY,
         inactivity_timeout: Some(std::time::Duration::from_secs(60 * 60)),
         auth_rejection_time: std::time::Duration::from_secs(5),
         auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-        keys: vec![keypair],
+        keys,
         ..Default::default()
     };
 
-    let port = options.port;
+    let listen = options.listen.clone();
+    let inetd = options.inetd;
+    let once = options.once;
+    let idle_exit = options.idle_exit;
+    let lifetime = options.lifetime;
+    let grace_period = options.grace_period;
+    let control_socket = options.control_socket.clone();
+    let control_socket_required = options.control_socket_required;
+
+    tokio::spawn(reload::run(
+        options.credentials.clone(),
+        options.reload_credentials.clone(),
+        options.watched_files.clone(),
+    ));
 
-    let server = Server {
+    let shells = Shells::new(options.detach_grace);
+    let registry = Registry::new(Audit::open(options.audit_log.as_deref())?, shells.clone());
+    // with the default path, a second server on the same machine runs without it rather than not at all
+    let _control = match control_socket {
+        Some(ref path) => match control::listen(path, registry.clone(), shells.clone()).await {
+            Ok(guard) => Some(guard),
+            Err(err) if control_socket_required => return Err(err),
+            Err(err) => {
+                log::warn!("{err:#}, the sessions and kick subcommands will not reach this server");
+                None
+            }
+        },
+        None => None,
+    };
+    let mut server = Server {
         clients: Arc::new(Mutex::new(HashMap::new())),
-        channel_pty_writers: Arc::new(Mutex::new(HashMap::new())),
+        ptys: Arc::new(Mutex::new(HashMap::new())),
+        shells,
+        attach: None,
         id: 0,
         options,
+        registry: registry.clone(),
+        connection: None,
+    };
+
+    let serve = async {
+        if inetd {
+            // serve exactly one connection over stdin/stdout
+            let handler = russh::server::Server::new_client(&mut server, None);
+            let session =
+                russh::server::run_stream(Arc::new(config), Stdio::new(), handler).await?;
+            // spawned so that it survives `serve` being dropped on shutdown
+            tokio::spawn(session).await??;
+            log::info!("Connection closed");
+        } else {
+            let config = Arc::new(config);
+            let listeners = listen
+                .into_iter()
+                .map(|addr| russh::server::run(config.clone(), addr, server.clone()));
+            futures::future::try_join_all(listeners).await?;
+        }
+        anyhow::Ok(())
+    };
+
+    let idle = lifecycle::idle(
+        registry.subscribe(),
+        shells.clone(),
+        idle_exit.unwrap_or_default(),
+    );
+    let expired = tokio::time::sleep(lifetime.unwrap_or_default());
+
+    // dropping `serve` stops accepting connections, open ones are handled by lifecycle::shutdown()
+    let (reason, grace) = tokio::select! {
+        res = serve => return res,
+        reason = lifecycle::terminate_signal() => (reason, grace_period),
+        _ = lifecycle::once(registry.subscribe()), if once => ("first connection ended", Duration::ZERO),
+        _ = idle, if idle_exit.is_some() => ("no session for too long", Duration::ZERO),
+        _ = expired, if lifetime.is_some() => ("server lifetime expired", Duration::ZERO),
     };
 
-    russh::server::run(Arc::new(config), format!(":::{port}"), server).await?;
+    lifecycle::shutdown(&registry, reason, grace).await;
     Ok(())
 }
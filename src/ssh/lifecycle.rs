@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+use super::{
+    registry::{Registry, Stats},
+    shells::Shells,
+};
+
+/// Resolves once an authenticated connection has ended
+pub async fn once(mut stats: watch::Receiver<Stats>) {
+    if stats
+        .wait_for(|stats| stats.finished_authenticated > 0)
+        .await
+        .is_err()
+    {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Resolves once no connection has been open for `idle`, and no detached shell waits for a reattach
+pub async fn idle(mut stats: watch::Receiver<Stats>, shells: Shells, idle: Duration) {
+    loop {
+        if stats.wait_for(|stats| stats.live == 0).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        // detached shells are hung up once their grace period ends, checked again after `idle`
+        if tokio::time::timeout(idle, stats.wait_for(|stats| stats.live > 0))
+            .await
+            .is_err()
+            && !shells.has_detached()
+        {
+            return;
+        }
+    }
+}
+
+/// Wait (at most `timeout`) for all connections to be closed
+pub async fn drained(mut stats: watch::Receiver<Stats>, timeout: Duration) -> bool {
+    tokio::time::timeout(timeout, stats.wait_for(|stats| stats.live == 0))
+        .await
+        .is_ok()
+}
+
+/// Resolves on SIGTERM or SIGINT
+pub async fn terminate_signal() -> &'static str {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to listen for SIGINT");
+    tokio::select! {
+        _ = sigterm.recv() => "received SIGTERM",
+        _ = sigint.recv() => "received SIGINT",
+    }
+}
+
+/// Stop the remaining connections: warn interactive sessions, wait up to `grace` for them to close by themselves,
+/// then terminate the shells and disconnect everyone left
+pub async fn shutdown(registry: &Registry, reason: &str, grace: Duration) {
+    let initial = registry.live();
+    log::info!("Shutting down ({reason}), {initial} connection(s) open");
+
+    let mut drained = initial == 0;
+    if !drained && !grace.is_zero() {
+        log::info!(
+            "Waiting up to {}s for sessions to finish. Send the signal again to stop now",
+            grace.as_secs()
+        );
+        registry
+            .broadcast(&format!(
+                "\r\n*** quickssh is shutting down ({reason}), this session will be closed in {}s ***\r\n",
+                grace.as_secs()
+            ))
+            .await;
+
+        drained = tokio::select! {
+            drained = self::drained(registry.subscribe(), grace) => drained,
+            reason = terminate_signal() => {
+                log::warn!("{reason} again, not waiting for sessions anymore");
+                false
+            }
+        };
+    }
+
+    let remaining = registry.live();
+    // detached shells outlive their connection, they are terminated even when everyone left
+    let terminated = registry.terminate_shells();
+    if !drained {
+        registry
+            .disconnect_all(&format!("quickssh is shutting down: {reason}"))
+            .await;
+        // give the sessions a chance to actually send the disconnect message
+        self::drained(registry.subscribe(), Duration::from_secs(1)).await;
+    }
+
+    log::info!(
+        "Shutdown complete: {} connection(s) closed gracefully, {remaining} forcibly closed, {terminated} shell process(es) terminated",
+        initial - remaining
+    );
+}
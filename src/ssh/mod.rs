@@ -1,7 +1,20 @@
+mod audit;
+pub mod control;
 mod events;
 pub mod init;
+mod lifecycle;
+mod metered;
+mod recording;
+mod registry;
+mod reload;
+mod sftp_check_file;
+mod sftp_dispatch;
 mod sftp_events;
+mod sftp_root;
 mod sftp_utils;
+mod shells;
+mod stdio;
 mod su_login;
+pub mod vfs;
 
-pub use init::{start_ssh_server, Server, ServerOptions};
+pub use init::{start_ssh_server, Credentials, Server, ServerOptions, User};
@@ -0,0 +1,123 @@
+use std::{
+    fs::{DirBuilder, File, OpenOptions},
+    io::Write,
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::Path,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use russh::ChannelId;
+use serde_json::json;
+
+/// Records a terminal session in the asciicast v2 format (https://docs.asciinema.org/manual/asciicast/v2/)
+pub struct Recorder {
+    started: Instant,
+    record_input: bool,
+    inner: Mutex<RecorderInner>,
+}
+
+struct RecorderInner {
+    file: File,
+    // bytes of a UTF-8 character split between two reads
+    pending_output: Vec<u8>,
+    pending_input: Vec<u8>,
+}
+
+pub struct RecordingHeader<'a> {
+    pub connection: usize,
+    // a connection can record several sessions at once, one per channel
+    pub channel: ChannelId,
+    pub user: &'a str,
+    pub term: &'a str,
+    pub shell: &'a str,
+    pub cols: u32,
+    pub rows: u32,
+}
+
+/// Decode the complete UTF-8 characters of `pending` + `data`, keeping an incomplete trailing one for later
+fn decode(pending: &mut Vec<u8>, data: &[u8]) -> String {
+    pending.extend_from_slice(data);
+    let complete = match std::str::from_utf8(pending) {
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        _ => pending.len(),
+    };
+    let text = String::from_utf8_lossy(&pending[..complete]).into_owned();
+    pending.drain(..complete);
+    text
+}
+
+impl Recorder {
+    pub fn create(
+        dir: &Path,
+        header: &RecordingHeader,
+        record_input: bool,
+    ) -> anyhow::Result<Recorder> {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let path = dir.join(format!(
+            "{timestamp}-{}-{}-{}.cast",
+            header.connection, header.channel, header.user
+        ));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Failed to create recording {}", path.display()))?;
+
+        let header = json!({
+            "version": 2,
+            "width": header.cols,
+            "height": header.rows,
+            "timestamp": timestamp,
+            "title": format!("{} (connection {})", header.user, header.connection),
+            "env": {"TERM": header.term, "SHELL": header.shell},
+        });
+        writeln!(file, "{header}")?;
+        log::info!("Recording session to {}", path.display());
+
+        Ok(Recorder {
+            started: Instant::now(),
+            record_input,
+            inner: Mutex::new(RecorderInner {
+                file,
+                pending_output: vec![],
+                pending_input: vec![],
+            }),
+        })
+    }
+
+    fn event(&self, inner: &mut RecorderInner, code: &str, data: &str) {
+        let line = json!([self.started.elapsed().as_secs_f64(), code, data]);
+        if let Err(err) = writeln!(inner.file, "{line}") {
+            log::error!("Failed to write to the session recording: {err}");
+        }
+    }
+
+    pub fn output(&self, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        let text = decode(&mut inner.pending_output, data);
+        if !text.is_empty() {
+            self.event(&mut inner, "o", &text);
+        }
+    }
+
+    pub fn input(&self, data: &[u8]) {
+        if !self.record_input {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let text = decode(&mut inner.pending_input, data);
+        if !text.is_empty() {
+            self.event(&mut inner, "i", &text);
+        }
+    }
+
+    pub fn resize(&self, cols: u32, rows: u32) {
+        let mut inner = self.inner.lock().unwrap();
+        self.event(&mut inner, "r", &format!("{cols}x{rows}"));
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use russh::{server::Handle, ChannelId, CryptoVec, Disconnect};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use super::{
+    audit::{Audit, Event},
+    shells::Shells,
+};
+
+pub struct Connection {
+    pub peer: Option<SocketAddr>,
+    pub user: Option<String>,
+    pub auth_method: Option<&'static str>,
+    pub started: SystemTime,
+    pub handle: Option<Handle>,
+    /// Open channels, with what they are used for (session, shell, sftp, direct-tcpip...)
+    pub channels: HashMap<ChannelId, String>,
+    /// Interactive shells of this connection, with their process id
+    pub shells: HashMap<ChannelId, Option<u32>>,
+    pub traffic: Arc<Traffic>,
+}
+
+/// Bytes transferred on the channels of a connection. Updated without locking the registry
+#[derive(Default)]
+pub struct Traffic {
+    pub received: AtomicU64,
+    pub sent: AtomicU64,
+}
+
+impl Traffic {
+    pub fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// What the control socket reports about a connection
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectionInfo {
+    pub id: usize,
+    pub peer: Option<String>,
+    pub user: Option<String>,
+    pub auth_method: Option<String>,
+    /// Unix timestamp, in seconds
+    pub started: u64,
+    pub channels: Vec<String>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Stats {
+    /// Connections currently open
+    pub live: usize,
+    /// Connections that were authenticated and have ended since startup
+    pub finished_authenticated: usize,
+}
+
+/// Keeps track of the live connections of the server
+#[derive(Clone)]
+pub struct Registry {
+    connections: Arc<Mutex<HashMap<usize, Connection>>>,
+    stats: Arc<watch::Sender<Stats>>,
+    next_id: Arc<AtomicUsize>,
+    audit: Audit,
+    // shells viewed by a connection are detached when it ends, even if it dropped without closing its channels
+    shells: Shells,
+}
+
+/// Unregisters its connection when the client handler holding it is dropped
+pub struct ConnectionGuard {
+    id: usize,
+    registry: Registry,
+    pub traffic: Arc<Traffic>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.unregister(self.id);
+    }
+}
+
+impl Registry {
+    pub fn new(audit: Audit, shells: Shells) -> Self {
+        Registry {
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::new(watch::channel(Stats::default()).0),
+            next_id: Arc::new(AtomicUsize::new(0)),
+            audit,
+            shells,
+        }
+    }
+
+    pub fn audit(&self) -> &Audit {
+        &self.audit
+    }
+
+    /// Connection ids are shared between all the listeners
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn register(&self, id: usize, peer: Option<SocketAddr>) -> ConnectionGuard {
+        let traffic = Arc::new(Traffic::default());
+        self.connections.lock().unwrap().insert(
+            id,
+            Connection {
+                peer,
+                user: None,
+                auth_method: None,
+                started: SystemTime::now(),
+                handle: None,
+                channels: HashMap::new(),
+                shells: HashMap::new(),
+                traffic: traffic.clone(),
+            },
+        );
+        self.stats.send_modify(|stats| stats.live += 1);
+        self.audit.log(
+            id,
+            Event::Connect {
+                peer: peer.map(|peer| peer.to_string()),
+            },
+        );
+
+        ConnectionGuard {
+            id,
+            registry: self.clone(),
+            traffic,
+        }
+    }
+
+    fn unregister(&self, id: usize) {
+        let connection = self.connections.lock().unwrap().remove(&id);
+        if let Some(connection) = connection {
+            log::debug!("connection {id} ended");
+            self.shells.detach_connection(id);
+            self.audit.log(
+                id,
+                Event::Disconnect {
+                    user: connection.user.as_deref(),
+                    duration: connection.started.elapsed().unwrap_or_default().as_secs(),
+                    bytes_received: connection.traffic.received.load(Ordering::Relaxed),
+                    bytes_sent: connection.traffic.sent.load(Ordering::Relaxed),
+                },
+            );
+            self.stats.send_modify(|stats| {
+                stats.live -= 1;
+                if connection.user.is_some() {
+                    stats.finished_authenticated += 1;
+                }
+            });
+        }
+    }
+
+    pub fn set_user(&self, id: usize, user: &str, auth_method: &'static str) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.user = Some(user.to_string());
+            connection.auth_method = Some(auth_method);
+        }
+    }
+
+    pub fn user(&self, id: usize) -> Option<String> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&id)
+            .and_then(|connection| connection.user.clone())
+    }
+
+    pub fn set_handle(&self, id: usize, handle: Handle) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.handle = Some(handle);
+        }
+    }
+
+    /// Add a channel, or change what an existing one is used for
+    pub fn set_channel(&self, id: usize, channel_id: ChannelId, kind: &str) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.channels.insert(channel_id, kind.to_string());
+        }
+    }
+
+    pub fn remove_channel(&self, id: usize, channel_id: ChannelId) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.channels.remove(&channel_id);
+        }
+    }
+
+    pub fn add_shell(&self, id: usize, channel_id: ChannelId, pid: Option<u32>) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.shells.insert(channel_id, pid);
+        }
+    }
+
+    pub fn remove_shell(&self, id: usize, channel_id: ChannelId) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&id) {
+            connection.shells.remove(&channel_id);
+        }
+    }
+
+    pub fn live(&self) -> usize {
+        self.stats.borrow().live
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Stats> {
+        self.stats.subscribe()
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, connection)| {
+                let mut channels: Vec<String> = connection.channels.values().cloned().collect();
+                channels.sort();
+                ConnectionInfo {
+                    id: *id,
+                    peer: connection.peer.map(|peer| peer.to_string()),
+                    user: connection.user.clone(),
+                    auth_method: connection.auth_method.map(str::to_string),
+                    started: connection
+                        .started
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                    channels,
+                    bytes_received: connection.traffic.received.load(Ordering::Relaxed),
+                    bytes_sent: connection.traffic.sent.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Disconnect a single connection, sending it the given reason
+    pub async fn disconnect(&self, id: usize, reason: &str) -> anyhow::Result<()> {
+        let handle = match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => connection.handle.clone(),
+            None => anyhow::bail!("No connection with id {id}"),
+        };
+        let Some(handle) = handle else {
+            anyhow::bail!("Connection {id} is not authenticated yet");
+        };
+
+        handle
+            .disconnect(
+                Disconnect::ByApplication,
+                reason.to_string(),
+                "en-US".to_string(),
+            )
+            .await
+            .map_err(|_| anyhow::anyhow!("Connection {id} is already closed"))
+    }
+
+    /// Write a message to every interactive shell. Returns the number of shells reached
+    pub async fn broadcast(&self, message: &str) -> usize {
+        let targets: Vec<(Handle, ChannelId)> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|connection| {
+                let handle = connection.handle.as_ref()?;
+                Some(
+                    connection
+                        .shells
+                        .keys()
+                        .map(|channel_id| (handle.clone(), *channel_id))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .flatten()
+            .collect();
+
+        let mut reached = 0;
+        for (handle, channel_id) in targets {
+            if handle
+                .data(channel_id, CryptoVec::from_slice(message.as_bytes()))
+                .await
+                .is_ok()
+            {
+                reached += 1;
+            }
+        }
+        reached
+    }
+
+    /// Send SIGHUP to every shell process, like a terminal hangup would, detached shells included.
+    /// Returns the number of processes signaled
+    pub fn terminate_shells(&self) -> usize {
+        let mut pids: Vec<u32> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|connection| connection.shells.values().flatten().copied())
+            .chain(self.shells.pids())
+            .collect();
+        pids.sort_unstable();
+        pids.dedup();
+
+        for pid in &pids {
+            log::debug!("Sending SIGHUP to shell process {pid}");
+            unsafe {
+                libc::kill(*pid as libc::pid_t, libc::SIGHUP);
+            }
+        }
+        pids.len()
+    }
+
+    /// Disconnect every connection, sending them the given reason
+    pub async fn disconnect_all(&self, reason: &str) {
+        let handles: Vec<Handle> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|connection| connection.handle.clone())
+            .collect();
+
+        for handle in handles {
+            let _ = handle
+                .disconnect(
+                    Disconnect::ByApplication,
+                    reason.to_string(),
+                    "en-US".to_string(),
+                )
+                .await;
+        }
+    }
+}
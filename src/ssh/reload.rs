@@ -0,0 +1,111 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+
+use super::init::{Credentials, CredentialsLoader};
+
+fn reload(credentials: &RwLock<Credentials>, loader: &CredentialsLoader) {
+    match loader() {
+        Ok(new) => {
+            log::info!(
+                "Credentials reloaded, {} user(s), {} public key(s) loaded",
+                new.users.len(),
+                new.users
+                    .iter()
+                    .map(|user| user.pubkeys.len())
+                    .sum::<usize>()
+            );
+            *credentials.write().unwrap() = new;
+        }
+        Err(err) => {
+            log::error!("Failed to reload credentials, keeping the previous ones: {err:#}")
+        }
+    }
+}
+
+/// Absolute paths under which changes to `path` are reported: the path itself in its canonical
+/// directory, and the file it points to if it is a symlink
+fn resolve(path: &Path) -> Vec<PathBuf> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut resolved = vec![];
+    match (std::fs::canonicalize(dir), path.file_name()) {
+        (Ok(dir), Some(name)) => resolved.push(dir.join(name)),
+        _ => log::warn!(
+            "Cannot watch {}: its directory does not exist",
+            path.display()
+        ),
+    }
+    if let Ok(target) = std::fs::canonicalize(path) {
+        if !resolved.contains(&target) {
+            resolved.push(target);
+        }
+    }
+    resolved
+}
+
+/// Watch the directories of `paths` (files are often replaced rather than modified in place)
+fn watch(paths: &[PathBuf], tx: mpsc::UnboundedSender<()>) -> anyhow::Result<RecommendedWatcher> {
+    let watched: Vec<PathBuf> = paths.iter().flat_map(|path| resolve(path)).collect();
+    let mut watcher = {
+        let watched = watched.clone();
+        notify::recommended_watcher(move |res: notify::Result<Event>| {
+            if let Ok(event) = res {
+                if event.paths.iter().any(|changed| watched.contains(changed)) {
+                    let _ = tx.send(());
+                }
+            }
+        })?
+    };
+
+    for path in &watched {
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        if let Err(err) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            log::warn!("Failed to watch {}: {err}", path.display());
+        } else {
+            log::info!("Watching {} for changes", path.display());
+        }
+    }
+    Ok(watcher)
+}
+
+/// Reload the credentials on SIGHUP, and when `watched` changes
+pub async fn run(
+    credentials: Arc<RwLock<Credentials>>,
+    loader: CredentialsLoader,
+    watched: Vec<PathBuf>,
+) {
+    let mut sighup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let _watcher = if watched.is_empty() {
+        None
+    } else {
+        watch(&watched, tx)
+            .map_err(|err| log::warn!("Failed to watch files for changes: {err}"))
+            .ok()
+    };
+
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => log::info!("Received SIGHUP, reloading credentials"),
+            Some(()) = rx.recv() => {
+                // editors usually write files in several steps
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                while rx.try_recv().is_ok() {}
+                log::info!("Watched files changed, reloading credentials");
+            }
+        }
+        reload(&credentials, &loader);
+    }
+}
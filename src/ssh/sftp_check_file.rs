@@ -0,0 +1,135 @@
+//! The `check-file-name` and `check-file-handle` extensions (draft-ietf-secsh-filexfer-extensions),
+//! hashing files on the server so clients can compare them without downloading them
+use std::io;
+
+use md5::Md5;
+use russh_sftp::protocol::StatusCode;
+use sha1::Sha1;
+use sha2::{digest::DynDigest, Digest, Sha256, Sha512};
+
+use super::sftp_utils::SftpError;
+use super::vfs::FileHandle;
+
+/// Smallest block size the draft allows, 0 aside
+const MIN_BLOCK_SIZE: u32 = 256;
+
+fn hasher(algorithm: &str) -> Option<Box<dyn DynDigest + Send>> {
+    Some(match algorithm {
+        "md5" => Box::new(Md5::new()),
+        "sha1" => Box::new(Sha1::new()),
+        "sha256" => Box::new(Sha256::new()),
+        "sha512" => Box::new(Sha512::new()),
+        _ => return None,
+    })
+}
+
+/// Hashes of a range of a file, one per block, or one for the whole range if `block_size` is 0.
+/// A `length` of 0 goes until the end of the file. Stops after `max_blocks` blocks, the client
+/// sees the shorter reply and can ask for the rest
+fn hash_blocks(
+    file: &dyn FileHandle,
+    hasher: &mut dyn DynDigest,
+    start: u64,
+    length: u64,
+    block_size: u64,
+    max_blocks: usize,
+) -> io::Result<Vec<u8>> {
+    let end = if length == 0 {
+        u64::MAX
+    } else {
+        start.saturating_add(length)
+    };
+    let block_size = if block_size == 0 {
+        u64::MAX
+    } else {
+        block_size
+    };
+
+    let mut hashes = vec![];
+    let mut buf = vec![0u8; 64 * 1024];
+    let (mut offset, mut in_block) = (start, 0);
+    loop {
+        let len = (buf.len() as u64)
+            .min(end - offset)
+            .min(block_size - in_block) as usize;
+        let read = match len {
+            0 => 0,
+            len => file.read_at(&mut buf[..len], offset)?,
+        };
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        offset += read as u64;
+        in_block += read as u64;
+        if in_block == block_size {
+            hashes.extend_from_slice(&hasher.finalize_reset());
+            in_block = 0;
+            if hashes.len() / hasher.output_size() == max_blocks {
+                log::debug!("check-file stopped after {max_blocks} blocks at offset {offset}");
+                return Ok(hashes);
+            }
+        }
+    }
+    // the last, partial block. An empty range still has a hash
+    if in_block > 0 || hashes.is_empty() {
+        hashes.extend_from_slice(&hasher.finalize_reset());
+    }
+    Ok(hashes)
+}
+
+fn put_string(buf: &mut Vec<u8>, string: &str) {
+    buf.extend_from_slice(&(string.len() as u32).to_be_bytes());
+    buf.extend_from_slice(string.as_bytes());
+}
+
+/// Reply to a check-file request: the first algorithm of `algorithms` (comma separated) that is
+/// supported, and the hashes of the blocks. There are only as many hashes as fit in `max_len` bytes
+pub fn check_file(
+    file: &dyn FileHandle,
+    algorithms: &str,
+    start: u64,
+    length: u64,
+    block_size: u32,
+    max_len: usize,
+) -> Result<Vec<u8>, SftpError> {
+    let Some((algorithm, mut hasher)) = algorithms
+        .split(',')
+        .find_map(|algorithm| Some((algorithm, hasher(algorithm)?)))
+    else {
+        log::warn!("Client requested check-file with unsupported algorithms {algorithms}");
+        return Err(SftpError::new(
+            StatusCode::OpUnsupported,
+            format!("unsupported algorithms {algorithms}"),
+        ));
+    };
+    if block_size != 0 && block_size < MIN_BLOCK_SIZE {
+        log::warn!("Client requested check-file with a block size of {block_size}");
+        return Err(SftpError::new(
+            StatusCode::Failure,
+            format!("block size below {MIN_BLOCK_SIZE}"),
+        ));
+    }
+
+    let mut reply = vec![];
+    put_string(&mut reply, "check-file");
+    put_string(&mut reply, algorithm);
+    let max_blocks = max_len.saturating_sub(reply.len()) / hasher.output_size();
+    if max_blocks == 0 {
+        return Err(SftpError::new(StatusCode::Failure, "hash too large"));
+    }
+    let hashes = hash_blocks(
+        file,
+        hasher.as_mut(),
+        start,
+        length,
+        block_size.into(),
+        max_blocks,
+    )
+    .map_err(|err| {
+        log::warn!("check-file failed: {err}");
+        SftpError::from(err)
+    })?;
+    reply.extend_from_slice(&hashes);
+    Ok(reply)
+}
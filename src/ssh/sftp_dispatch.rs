@@ -0,0 +1,331 @@
+//! Reads the SFTP requests of a channel and runs them. Unlike `russh_sftp::server::run`,
+//! requests pipelined by a client run at the same time, except those on the same handle or path
+//! that run in the order they were sent
+use super::sftp_events::{error_status, SftpSession, MAX_PACKET};
+use super::sftp_utils::{ExtendedData, SftpError};
+use bytes::Bytes;
+use russh_sftp::{
+    protocol::{Packet, StatusCode},
+    server::Handler,
+};
+use std::{collections::HashMap, io::ErrorKind, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{
+        mpsc,
+        oneshot::{self, error::TryRecvError},
+        Semaphore,
+    },
+};
+
+/// Requests of a channel running at the same time. OpenSSH clients pipeline up to 64
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Serve SFTP on a channel stream, until the client closes it
+pub fn run<S>(stream: S, session: SftpSession)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (replies, mut outgoing) = mpsc::channel::<Packet>(MAX_IN_FLIGHT);
+
+    tokio::spawn(async move {
+        while let Some(reply) = outgoing.recv().await {
+            let bytes = match Bytes::try_from(reply) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    log::error!("Failed to encode an SFTP reply: {err}");
+                    continue;
+                }
+            };
+            if let Err(err) = writer.write_all(&bytes).await {
+                log::debug!("SFTP channel closed: {err}");
+                break;
+            }
+        }
+    });
+    tokio::spawn(dispatch(reader, session, replies));
+}
+
+async fn dispatch<R: AsyncRead + Unpin>(
+    mut reader: R,
+    mut session: SftpSession,
+    replies: mpsc::Sender<Packet>,
+) {
+    let slots = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let mut queues = Queues::default();
+    loop {
+        let mut bytes = match read_packet(&mut reader).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                if err.kind() != ErrorKind::UnexpectedEof {
+                    log::warn!("Failed to read an SFTP request: {err}");
+                }
+                break;
+            }
+        };
+        let request = match Packet::try_from(&mut bytes) {
+            Ok(request) => request,
+            Err(err) => {
+                log::warn!("Invalid SFTP request: {err}");
+                let _ = replies.send(error(0, StatusCode::BadMessage.into())).await;
+                continue;
+            }
+        };
+
+        // the version is agreed on before the client sends anything else
+        if let Packet::Init(init) = request {
+            let reply = match session.init(init.version, init.extensions).await {
+                Ok(version) => Packet::Version(version),
+                Err(err) => error(init.version, err),
+            };
+            let _ = replies.send(reply).await;
+            continue;
+        }
+
+        // waiting for a slot stops reading, so a client cannot queue requests without bounds
+        let Ok(slot) = slots.clone().acquire_owned().await else {
+            break;
+        };
+        let mut turn = queues.enter(queue_names(&request));
+        let (session, replies) = (session.clone(), replies.clone());
+        tokio::spawn(async move {
+            turn.wait().await;
+            let reply = process(session, request).await;
+            // sent before the turn ends, so replies on a handle keep the order of the requests
+            let _ = replies.send(reply).await;
+            drop(turn);
+            drop(slot);
+        });
+    }
+}
+
+async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Bytes> {
+    let len = reader.read_u32().await?;
+    if u64::from(len) > MAX_PACKET {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("request of {len} bytes, larger than {MAX_PACKET}"),
+        ));
+    }
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(Bytes::from(buf))
+}
+
+fn error(id: u32, err: SftpError) -> Packet {
+    Packet::Status(error_status(id, err))
+}
+
+async fn process(mut session: SftpSession, request: Packet) -> Packet {
+    let id = request.get_request_id();
+    let reply = match request {
+        Packet::Open(open) => session
+            .open(id, open.filename, open.pflags, open.attrs)
+            .await
+            .map(Packet::Handle),
+        Packet::Close(close) => session.close(id, close.handle).await.map(Packet::Status),
+        Packet::Read(read) => session
+            .read(id, read.handle, read.offset, read.len)
+            .await
+            .map(Packet::Data),
+        Packet::Write(write) => session
+            .write(id, write.handle, write.offset, write.data)
+            .await
+            .map(Packet::Status),
+        Packet::Lstat(lstat) => session.lstat(id, lstat.path).await.map(Packet::Attrs),
+        Packet::Fstat(fstat) => session.fstat(id, fstat.handle).await.map(Packet::Attrs),
+        Packet::SetStat(setstat) => session
+            .setstat(id, setstat.path, setstat.attrs)
+            .await
+            .map(Packet::Status),
+        Packet::FSetStat(fsetstat) => session
+            .fsetstat(id, fsetstat.handle, fsetstat.attrs)
+            .await
+            .map(Packet::Status),
+        Packet::OpenDir(opendir) => session.opendir(id, opendir.path).await.map(Packet::Handle),
+        Packet::ReadDir(readdir) => session.readdir(id, readdir.handle).await.map(Packet::Name),
+        Packet::Remove(remove) => session
+            .remove(id, remove.filename)
+            .await
+            .map(Packet::Status),
+        Packet::MkDir(mkdir) => session
+            .mkdir(id, mkdir.path, mkdir.attrs)
+            .await
+            .map(Packet::Status),
+        Packet::RmDir(rmdir) => session.rmdir(id, rmdir.path).await.map(Packet::Status),
+        Packet::RealPath(realpath) => session.realpath(id, realpath.path).await.map(Packet::Name),
+        Packet::Stat(stat) => session.stat(id, stat.path).await.map(Packet::Attrs),
+        Packet::Rename(rename) => session
+            .rename(id, rename.oldpath, rename.newpath)
+            .await
+            .map(Packet::Status),
+        Packet::ReadLink(readlink) => session.readlink(id, readlink.path).await.map(Packet::Name),
+        Packet::Symlink(symlink) => session
+            .symlink(id, symlink.linkpath, symlink.targetpath)
+            .await
+            .map(Packet::Status),
+        Packet::Extended(extended) => session.extended(id, extended.request, extended.data).await,
+        _ => {
+            log::warn!("Client sent a reply packet as a request");
+            Err(StatusCode::BadMessage.into())
+        }
+    };
+    reply.unwrap_or_else(|err| error(id, err))
+}
+
+/// Handles and paths a request works on. Requests sharing one run in order
+fn queue_names(request: &Packet) -> Vec<String> {
+    let mut names = match request {
+        Packet::Close(close) => vec![handle(&close.handle)],
+        Packet::Read(read) => vec![handle(&read.handle)],
+        Packet::Write(write) => vec![handle(&write.handle)],
+        Packet::Fstat(fstat) => vec![handle(&fstat.handle)],
+        Packet::FSetStat(fsetstat) => vec![handle(&fsetstat.handle)],
+        Packet::ReadDir(readdir) => vec![handle(&readdir.handle)],
+        Packet::Open(open) => vec![path(&open.filename)],
+        Packet::Lstat(lstat) => vec![path(&lstat.path)],
+        Packet::SetStat(setstat) => vec![path(&setstat.path)],
+        Packet::OpenDir(opendir) => vec![path(&opendir.path)],
+        Packet::Remove(remove) => vec![path(&remove.filename)],
+        Packet::MkDir(mkdir) => vec![path(&mkdir.path)],
+        Packet::RmDir(rmdir) => vec![path(&rmdir.path)],
+        Packet::RealPath(realpath) => vec![path(&realpath.path)],
+        Packet::Stat(stat) => vec![path(&stat.path)],
+        Packet::ReadLink(readlink) => vec![path(&readlink.path)],
+        Packet::Rename(rename) => vec![path(&rename.oldpath), path(&rename.newpath)],
+        Packet::Symlink(symlink) => vec![path(&symlink.linkpath), path(&symlink.targetpath)],
+        // a malformed request is refused by its handler, it does not need a queue
+        Packet::Extended(extended) => {
+            extended_queue_names(&extended.request, &extended.data).unwrap_or_default()
+        }
+        _ => vec![],
+    };
+    // a request waiting for itself would never run
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn extended_queue_names(request: &str, data: &[u8]) -> Result<Vec<String>, StatusCode> {
+    let mut fields = ExtendedData::new(data);
+    Ok(match request {
+        "fstatvfs@openssh.com" | "fsync@openssh.com" | "check-file-handle" => {
+            vec![handle(&fields.string()?)]
+        }
+        "copy-data" => {
+            let read_handle = fields.string()?;
+            let (_offset, _length) = (fields.u64()?, fields.u64()?);
+            vec![handle(&read_handle), handle(&fields.string()?)]
+        }
+        "posix-rename@openssh.com" | "hardlink@openssh.com" => {
+            vec![path(&fields.string()?), path(&fields.string()?)]
+        }
+        "statvfs@openssh.com"
+        | "lsetstat@openssh.com"
+        | "expand-path@openssh.com"
+        | "check-file-name" => vec![path(&fields.string()?)],
+        _ => vec![],
+    })
+}
+
+fn handle(handle: &str) -> String {
+    format!("handle {handle}")
+}
+
+fn path(path: &str) -> String {
+    format!("path {path}")
+}
+
+/// Last request of each handle and path, that the next one waits for
+#[derive(Default)]
+struct Queues {
+    last: HashMap<String, oneshot::Receiver<()>>,
+}
+
+impl Queues {
+    fn enter(&mut self, names: Vec<String>) -> Turn {
+        // forget the queues whose last request is done
+        self.last
+            .retain(|_, done| matches!(done.try_recv(), Err(TryRecvError::Empty)));
+
+        let (mut previous, mut done) = (vec![], vec![]);
+        for name in names {
+            let (sender, receiver) = oneshot::channel();
+            previous.extend(self.last.insert(name, receiver));
+            done.push(sender);
+        }
+        Turn {
+            previous,
+            _done: done,
+        }
+    }
+}
+
+/// Place of a request in its queues. The next requests run once it is dropped
+struct Turn {
+    previous: Vec<oneshot::Receiver<()>>,
+    _done: Vec<oneshot::Sender<()>>,
+}
+
+impl Turn {
+    async fn wait(&mut self) {
+        while let Some(previous) = self.previous.last_mut() {
+            // the sender is dropped, never used, when the previous request is done
+            let _ = previous.await;
+            self.previous.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut data = (value.len() as u32).to_be_bytes().to_vec();
+        data.extend(value.as_bytes());
+        data
+    }
+
+    #[test]
+    fn requests_on_a_handle_run_in_order() {
+        let mut queues = Queues::default();
+        let mut first = queues.enter(vec![handle("1")]);
+        let mut second = queues.enter(vec![handle("1")]);
+        let mut other = queues.enter(vec![handle("2")]);
+
+        assert!(first.wait().now_or_never().is_some());
+        assert!(other.wait().now_or_never().is_some());
+        assert!(second.wait().now_or_never().is_none());
+        drop(first);
+        assert!(second.wait().now_or_never().is_some());
+    }
+
+    #[test]
+    fn finished_queues_are_forgotten() {
+        let mut queues = Queues::default();
+        drop(queues.enter(vec![path("/a"), path("/b")]));
+        let mut next = queues.enter(vec![path("/c")]);
+        assert_eq!(queues.last.len(), 1);
+        assert!(next.wait().now_or_never().is_some());
+    }
+
+    #[test]
+    fn copy_data_waits_for_both_handles() {
+        let mut data = string("3");
+        data.extend(0u64.to_be_bytes());
+        data.extend(0u64.to_be_bytes());
+        data.extend(string("4"));
+        data.extend(0u64.to_be_bytes());
+        assert_eq!(
+            extended_queue_names("copy-data", &data),
+            Ok(vec![handle("3"), handle("4")])
+        );
+        assert_eq!(
+            extended_queue_names("copy-data", &string("3")),
+            Err(StatusCode::BadMessage)
+        );
+    }
+}
@@ -1,60 +1,428 @@
 /// inspired from https://github.com/AspectUnk/russh-sftp/blob/master/examples/server.rs
-use super::sftp_utils::*;
+use super::audit::{Audit, Event};
+use super::sftp_check_file::check_file;
+use super::sftp_root::SftpRoot;
+use super::sftp_utils::{encode_statvfs, longname, ExtendedData, SftpError};
+use super::vfs::{self, FileHandle, Filesystem, OpenOptions, ReadDir};
+use crate::cli::SftpMode;
 use async_trait::async_trait;
 use log::info;
 use russh_sftp::protocol::{
-    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+    Attrs, Data, ExtendedReply, File, FileAttributes, Handle, Name, OpenFlags, Packet, Status,
+    StatusCode, Version,
 };
 use std::{
     collections::HashMap,
-    fs::{OpenOptions, ReadDir},
-    io::ErrorKind,
-    os::unix::fs::FileExt,
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex, MutexGuard},
 };
 
+/// Entries sent per `Name` packet, like OpenSSH. Keeps packets small on huge directories
+const READDIR_BATCH: usize = 100;
+
 enum ReadDirRequest {
     Todo(ReadDir),
     Done,
 }
 
+struct OpenFile {
+    // as the client gave it, for the audit log
+    path: String,
+    // shared with the blocking pool while a request is running
+    file: Arc<dyn FileHandle>,
+}
+
+/// A file opened for writing, to report how much was written when it is closed
+struct WriteTotal {
+    path: String,
+    bytes: u64,
+}
+
+/// Open handles of a session, shared by its requests running at the same time
+struct Handles {
+    dirs: HashMap<String, ReadDirRequest>,
+    files: HashMap<String, OpenFile>,
+    write_totals: HashMap<String, WriteTotal>,
+    counter: u32,
+    audit: Audit,
+    connection: usize,
+}
+
+/// Files still open when the client goes away are never closed, their writes are logged here
+impl Drop for Handles {
+    fn drop(&mut self) {
+        for (_, total) in self.write_totals.drain() {
+            self.audit.log(
+                self.connection,
+                Event::SftpWrite {
+                    path: &total.path,
+                    bytes: total.bytes,
+                },
+            );
+        }
+    }
+}
+
 impl SftpSession {
-    fn new_handle(&mut self) -> String {
-        self.handle_counter += 1;
-        self.handle_counter.to_string()
+    pub fn new(
+        fs: Arc<dyn Filesystem>,
+        root: SftpRoot,
+        mode: SftpMode,
+        audit: Audit,
+        connection: usize,
+    ) -> Self {
+        SftpSession {
+            fs,
+            root: Arc::new(root),
+            mode,
+            version: None,
+            handles: Arc::new(Mutex::new(Handles {
+                dirs: HashMap::new(),
+                files: HashMap::new(),
+                write_totals: HashMap::new(),
+                counter: 0,
+                audit: audit.clone(),
+                connection,
+            })),
+            audit,
+            connection,
+        }
     }
+
+    /// Never held across an await, requests of the session run concurrently
+    fn handles(&self) -> MutexGuard<'_, Handles> {
+        self.handles.lock().unwrap()
+    }
+
+    fn new_handle(&self) -> String {
+        let mut handles = self.handles();
+        handles.counter += 1;
+        handles.counter.to_string()
+    }
+
+    fn audit(&self, event: Event) {
+        self.audit.log(self.connection, event);
+    }
+
+    /// Error for an operation the SFTP mode of the user does not allow
+    fn refuse(&self, operation: &str) -> SftpError {
+        log::warn!("Refused SFTP {operation} in {:?} mode", self.mode);
+        SftpError::new(
+            StatusCode::PermissionDenied,
+            format!("{operation} is not allowed"),
+        )
+    }
+
+    /// Error for an operation that is not implemented. Clients probe for them, it is not a problem
+    fn unsupported(&self, operation: &str) -> SftpError {
+        log::debug!("Client asked for the unimplemented SFTP operation {operation}");
+        SftpError::new(
+            StatusCode::OpUnsupported,
+            format!("{operation} is not supported"),
+        )
+    }
+
+    fn allow(&self, allowed: bool, operation: &str) -> Result<(), SftpError> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(self.refuse(operation))
+        }
+    }
+
+    /// Permissions a client may set. Only read-write users get setuid, setgid and sticky bits
+    fn allowed_mode(&self, mode: u32) -> u32 {
+        if self.mode.can_modify() {
+            mode & 0o7777
+        } else {
+            mode & 0o777
+        }
+    }
+
+    /// Run filesystem calls on the blocking thread pool of tokio. Done on the async workers,
+    /// a slow disk or a large directory would stall every connection
+    async fn unblock<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&dyn Filesystem, &SftpRoot) -> Result<T, SftpError> + Send + 'static,
+    ) -> Result<T, SftpError> {
+        let (fs, root) = (self.fs.clone(), self.root.clone());
+        tokio::task::spawn_blocking(move || op(fs.as_ref(), &root))
+            .await
+            .map_err(|err| {
+                log::error!("SFTP operation failed: {err}");
+                SftpError::new(StatusCode::Failure, "internal error")
+            })?
+    }
+
+    fn file(&self, handle: &str) -> io::Result<Arc<dyn FileHandle>> {
+        match self.handles().files.get(handle) {
+            Some(open) => Ok(open.file.clone()),
+            None => Err(invalid_handle(handle)),
+        }
+    }
+
+    /// setstat, and lsetstat when `follow` is false. The outer error is for requests refused
+    /// before reaching the filesystem
+    async fn set_attributes(
+        &mut self,
+        path: String,
+        attrs: FileAttributes,
+        follow: bool,
+    ) -> Result<io::Result<()>, SftpError> {
+        self.allow(self.mode.can_modify(), "setstat")?;
+        let res = {
+            let (path, attrs) = (path.clone(), attrs.clone());
+            self.unblock(move |fs, root| {
+                Ok(if follow {
+                    fs.set_attributes(&root.resolve(&path)?, &attrs)
+                } else {
+                    fs.set_symlink_attributes(&root.resolve_nofollow(&path)?, &attrs)
+                })
+            })
+            .await?
+        };
+        self.audit(Event::SftpSetstat {
+            path: &path,
+            attrs: format!("{attrs:?}"),
+            success: res.is_ok(),
+        });
+        Ok(res)
+    }
+
+    /// rename, and posix-rename when `overwrite` is true
+    async fn rename_paths(
+        &mut self,
+        oldpath: String,
+        newpath: String,
+        overwrite: bool,
+    ) -> Result<io::Result<()>, SftpError> {
+        // renaming over an existing file would overwrite it, so drop boxes cannot rename
+        self.allow(self.mode.can_modify(), "rename")?;
+        let res = {
+            let (oldpath, newpath) = (oldpath.clone(), newpath.clone());
+            self.unblock(move |fs, root| {
+                let (from, to) = (
+                    root.resolve_nofollow(&oldpath)?,
+                    root.resolve_nofollow(&newpath)?,
+                );
+                // like OpenSSH, a plain rename never replaces a file
+                if !overwrite && fs.symlink_metadata(&to).is_ok() {
+                    return Ok(Err(ErrorKind::AlreadyExists.into()));
+                }
+                Ok(fs.rename(&from, &to))
+            })
+            .await?
+        };
+        self.audit(Event::SftpRename {
+            from: &oldpath,
+            to: &newpath,
+            success: res.is_ok(),
+        });
+        Ok(res)
+    }
+
+    /// Canonical client path of a client path
+    async fn real_path(&self, path: String) -> Result<String, SftpError> {
+        let res = self
+            .unblock(move |fs, root| Ok(fs.canonicalize(&root.resolve(&path)?)))
+            .await?;
+        let path = tr(res)?;
+        self.root.check(&path)?;
+        Ok(self.root.present(&path))
+    }
+
+    /// Copy a range of a file into another, for `copy-data`. A length of 0 copies until the end
+    async fn copy_data(
+        &mut self,
+        read_handle: String,
+        read_offset: u64,
+        length: u64,
+        write_handle: String,
+        write_offset: u64,
+    ) -> Result<io::Result<()>, SftpError> {
+        self.allow(self.mode.can_read(), "copy-data")?;
+        let (source, destination) = match (self.file(&read_handle), self.file(&write_handle)) {
+            (Ok(source), Ok(destination)) => (source, destination),
+            (Err(err), _) | (_, Err(err)) => return Ok(Err(err)),
+        };
+        let Some(path) = self
+            .handles()
+            .write_totals
+            .get(&write_handle)
+            .map(|total| total.path.clone())
+        else {
+            return Ok(Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "handle not opened for writing",
+            )));
+        };
+        let limit = if length == 0 { u64::MAX } else { length };
+        if read_handle == write_handle
+            && read_offset.max(write_offset) < read_offset.min(write_offset).saturating_add(limit)
+        {
+            return Ok(Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "overlapping ranges",
+            )));
+        }
+
+        let res = self
+            .unblock(move |_, _| {
+                Ok(copy_range(
+                    source.as_ref(),
+                    destination.as_ref(),
+                    read_offset,
+                    limit,
+                    write_offset,
+                ))
+            })
+            .await?;
+        if let Ok(copied) = res {
+            log::info!("Copied {copied} bytes into {path}");
+            if let Some(total) = self.handles().write_totals.get_mut(&write_handle) {
+                total.bytes += copied;
+            }
+        }
+        Ok(res.map(|_| ()))
+    }
+
+    /// Hash a file given by name or by handle, for `check-file-name` and `check-file-handle`
+    async fn check_file(
+        &self,
+        target: CheckFileTarget,
+        algorithms: String,
+        start: u64,
+        length: u64,
+        block_size: u32,
+    ) -> Result<Vec<u8>, SftpError> {
+        // hashes tell what a file holds
+        self.allow(self.mode.can_read(), "check-file")?;
+        self.unblock(move |fs, root| {
+            let file = match target {
+                CheckFileTarget::Handle(file) => file,
+                CheckFileTarget::Name(path) => {
+                    let options = OpenOptions {
+                        read: true,
+                        ..Default::default()
+                    };
+                    Arc::from(tr(fs.open(&root.resolve(&path)?, options))?)
+                }
+            };
+            check_file(
+                file.as_ref(),
+                &algorithms,
+                start,
+                length,
+                block_size,
+                MAX_READ as usize,
+            )
+        })
+        .await
+    }
+}
+
+/// Copy up to `limit` bytes, stopping at the end of `source`. Returns how much was copied
+fn copy_range(
+    source: &dyn FileHandle,
+    destination: &dyn FileHandle,
+    read_offset: u64,
+    limit: u64,
+    write_offset: u64,
+) -> io::Result<u64> {
+    let mut buf = vec![0u8; MAX_READ as usize];
+    let mut copied = 0u64;
+    while copied < limit {
+        let len = buf
+            .len()
+            .min((limit - copied).try_into().unwrap_or(usize::MAX));
+        let read = source.read_at(&mut buf[..len], read_offset + copied)?;
+        if read == 0 {
+            break;
+        }
+        destination.write_all_at(&buf[..read], write_offset + copied)?;
+        copied += read as u64;
+    }
+    Ok(copied)
 }
 
-#[derive(Default)]
+/// File a check-file request hashes
+enum CheckFileTarget {
+    Name(String),
+    Handle(Arc<dyn FileHandle>),
+}
+
+/// Largest packet and read, as advertised by `limits@openssh.com`. The values of OpenSSH
+pub const MAX_PACKET: u64 = 256 * 1024;
+const MAX_READ: u64 = MAX_PACKET - 1024;
+
+/// OpenSSH extensions, with their version
+const EXTENSIONS: [(&str, &str); 11] = [
+    ("posix-rename@openssh.com", "1"),
+    ("statvfs@openssh.com", "2"),
+    ("fstatvfs@openssh.com", "2"),
+    ("hardlink@openssh.com", "1"),
+    ("fsync@openssh.com", "1"),
+    ("lsetstat@openssh.com", "1"),
+    ("limits@openssh.com", "1"),
+    ("expand-path@openssh.com", "1"),
+    ("copy-data", "1"),
+    ("check-file-name", "1"),
+    ("check-file-handle", "1"),
+];
+
+/// Cloned for each request, see `sftp_dispatch`
+#[derive(Clone)]
 pub struct SftpSession {
+    fs: Arc<dyn Filesystem>,
+    root: Arc<SftpRoot>,
+    mode: SftpMode,
     version: Option<u32>,
-    dir_handles: HashMap<String, ReadDirRequest>,
-    file_handles: HashMap<String, std::fs::File>,
-    handle_counter: u32,
+    handles: Arc<Mutex<Handles>>,
+    audit: Audit,
+    connection: usize,
 }
 
-/// same as below, but for anyhow
-fn tr_ah<T>(res: anyhow::Result<T>) -> Result<T, StatusCode> {
+/// "tr" means "translate"
+/// This functions translates a filesystem error into an error reply, with the error of the OS as
+/// the message
+fn tr<T>(res: io::Result<T>) -> Result<T, SftpError> {
+    res.map_err(|err| {
+        log::warn!("SFTP operation failed: {err}");
+        SftpError::from(err)
+    })
+}
+
+/// Status reply of an operation, with the error of the OS as the message if it failed
+fn status(id: u32, res: io::Result<()>) -> Status {
     match res {
-        Ok(value) => Ok(value),
+        Ok(()) => status_ok(id),
         Err(err) => {
-            log::error!("An error occured: {err}");
-            Err(StatusCode::Failure)
+            log::warn!("SFTP operation failed: {err}");
+            error_status(id, err.into())
         }
     }
 }
-/// "tr" means "translate"
-/// This functions translates any kind of error into a StatusCode
-/// If someone knows how to do this using the ? operator, please open a PR
-fn tr<T>(res: Result<T, impl std::error::Error>) -> Result<T, StatusCode> {
-    match res {
-        Ok(value) => Ok(value),
-        Err(err) => {
-            log::error!("An error occured: {err}");
-            Err(StatusCode::Failure)
-        }
+
+/// Status reply of a failed request
+pub fn error_status(id: u32, err: SftpError) -> Status {
+    Status {
+        id,
+        status_code: err.code,
+        error_message: err.message,
+        language_tag: "en-US".to_string(),
     }
 }
 
+/// SFTP v3 has no SSH_FX_INVALID_HANDLE, this is a failure with a message
+fn invalid_handle(handle: &str) -> io::Error {
+    log::warn!("Client used non-existant handle: {handle}");
+    io::Error::new(ErrorKind::InvalidInput, format!("invalid handle {handle}"))
+}
+
+fn extended_reply(id: u32, data: Vec<u8>) -> Packet {
+    Packet::ExtendedReply(ExtendedReply { id, data })
+}
+
 fn status_ok(id: u32) -> Status {
     Status {
         id,
@@ -66,31 +434,23 @@ fn status_ok(id: u32) -> Status {
 
 #[async_trait]
 impl russh_sftp::server::Handler for SftpSession {
-    type Error = StatusCode;
+    type Error = SftpError;
 
+    // only reached through the default methods of the trait, sftp_dispatch calls them all by name
     fn unimplemented(&self) -> Self::Error {
-        let bt = std::backtrace::Backtrace::force_capture();
-        log::warn!(
-            "Client asked for an unimplemented operation. Stacktrace: {}",
-            bt
-        );
-
-        StatusCode::OpUnsupported
+        self.unsupported("this request")
     }
 
     async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
         info!("stat({}, {})", id, path);
 
-        match std::fs::metadata(path) {
-            Ok(md) => Ok(Attrs {
-                id,
-                attrs: metadata_to_file_attributes(&md),
-            }),
-            Err(err) if err.kind() == ErrorKind::NotFound => Err(StatusCode::NoSuchFile),
-            Err(err) => {
-                log::error!("Error occured in stat(): {err}");
-                Err(StatusCode::Failure)
-            }
+        let res = self
+            .unblock(move |fs, root| Ok(fs.metadata(&root.resolve(&path)?)))
+            .await?;
+        match tr(res)? {
+            // drop boxes only show directories, so clients can upload into them
+            attrs if !vfs::is_dir(&attrs) && !self.mode.can_read() => Err(self.refuse("stat")),
+            attrs => Ok(Attrs { id, attrs }),
         }
     }
 
@@ -98,39 +458,21 @@ impl russh_sftp::server::Handler for SftpSession {
     async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
         info!("lstat({}, {})", id, path);
 
-        match std::fs::symlink_metadata(path) {
-            Ok(md) => Ok(Attrs {
-                id,
-                attrs: metadata_to_file_attributes(&md),
-            }),
-            Err(err) if err.kind() == ErrorKind::NotFound => Err(StatusCode::NoSuchFile),
-            Err(err) => {
-                log::error!("Error occured in stat(): {err}");
-                Err(StatusCode::Failure)
-            }
+        let res = self
+            .unblock(move |fs, root| Ok(fs.symlink_metadata(&root.resolve_nofollow(&path)?)))
+            .await?;
+        match tr(res)? {
+            attrs if !vfs::is_dir(&attrs) && !self.mode.can_read() => Err(self.refuse("lstat")),
+            attrs => Ok(Attrs { id, attrs }),
         }
     }
 
     async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
         log::info!("fstat({}, {})", id, handle);
 
-        if let Some(file) = self.file_handles.get(&handle) {
-            match file.metadata() {
-                Ok(md) => Ok(Attrs {
-                    id,
-                    attrs: metadata_to_file_attributes(&md),
-                }),
-                Err(err) if err.kind() == ErrorKind::NotFound => Err(StatusCode::NoSuchFile),
-                Err(err) => {
-                    log::error!("Error occured in fstat(): {err}");
-                    Err(StatusCode::Failure)
-                }
-            }
-        } else {
-            log::warn!("Client requested fstat() on non-existant handle: {handle}");
-            // TODO use SSH_FX_INVALID_HANDLE
-            Err(Self::Error::Failure)
-        }
+        let file = tr(self.file(&handle))?;
+        let attrs = tr(self.unblock(move |_, _| Ok(file.metadata())).await?)?;
+        Ok(Attrs { id, attrs })
     }
 
     async fn setstat(
@@ -140,8 +482,40 @@ impl russh_sftp::server::Handler for SftpSession {
         attrs: FileAttributes,
     ) -> Result<Status, Self::Error> {
         log::info!("setstat({}, {}, {:?})", id, path, attrs);
-        tr_ah(apply_file_attributes(path, &attrs))?;
-        Ok(status_ok(id))
+        let res = self.set_attributes(path, attrs, true).await?;
+        Ok(status(id, res))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        log::info!("fsetstat({}, {}, {:?})", id, handle, attrs);
+        let (path, file, uploaded) = {
+            let handles = self.handles();
+            let Some(open) = handles.files.get(&handle) else {
+                return Ok(status(id, Err(invalid_handle(&handle))));
+            };
+            let uploaded = handles.write_totals.contains_key(&handle);
+            (open.path.clone(), open.file.clone(), uploaded)
+        };
+        // drop boxes can set the attributes of the files they upload
+        self.allow(self.mode.can_modify() || uploaded, "fsetstat")?;
+        let mut attrs = attrs;
+        attrs.permissions = attrs.permissions.map(|mode| self.allowed_mode(mode));
+        let res = {
+            let attrs = attrs.clone();
+            self.unblock(move |_, _| Ok(file.set_attributes(&attrs)))
+                .await?
+        };
+        self.audit(Event::SftpSetstat {
+            path: &path,
+            attrs: format!("{attrs:?}"),
+            success: res.is_ok(),
+        });
+        Ok(status(id, res))
     }
 
     async fn init(
@@ -151,42 +525,47 @@ impl russh_sftp::server::Handler for SftpSession {
     ) -> Result<Version, Self::Error> {
         if self.version.is_some() {
             log::error!("duplicate SSH_FXP_VERSION packet");
-            return Err(StatusCode::ConnectionLost);
+            return Err(StatusCode::ConnectionLost.into());
         }
 
         self.version = Some(version);
         info!("version: {:?}, extensions: {:?}", self.version, extensions);
-        Ok(Version::new())
+        let mut version = Version::new();
+        version.extensions = EXTENSIONS
+            .iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect();
+        Ok(version)
     }
 
     async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
         info!("close({}, {})", id, handle);
-        if self.file_handles.remove(&handle).is_some() || self.dir_handles.remove(&handle).is_some()
-        {
+        let mut handles = self.handles();
+        if let Some(total) = handles.write_totals.remove(&handle) {
+            self.audit(Event::SftpWrite {
+                path: &total.path,
+                bytes: total.bytes,
+            });
+        }
+        if handles.files.remove(&handle).is_some() || handles.dirs.remove(&handle).is_some() {
             Ok(status_ok(id))
         } else {
-            Err(StatusCode::Failure)
+            Ok(status(id, Err(invalid_handle(&handle))))
         }
     }
 
     async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
         info!("opendir({}, {})", id, path);
+        self.allow(self.mode.can_read(), "opendir")?;
         let handle = self.new_handle();
 
-        let paths_res = std::fs::read_dir(path);
-
-        let paths = match paths_res {
-            Ok(paths) => paths,
-            Err(ref err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
-                return Err(StatusCode::PermissionDenied);
-            }
-            Err(ref err) => {
-                log::error!("opendir({}, {}) failed: {}", id, handle, err);
-                return Err(StatusCode::Failure);
-            }
-        };
+        let paths_res = self
+            .unblock(move |fs, root| Ok(fs.read_dir(&root.resolve(&path)?)))
+            .await?;
+        let paths = tr(paths_res)?;
 
-        self.dir_handles
+        self.handles()
+            .dirs
             .insert(handle.clone(), ReadDirRequest::Todo(paths));
         Ok(Handle { id, handle })
     }
@@ -194,61 +573,60 @@ impl russh_sftp::server::Handler for SftpSession {
     async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
         info!("readdir({}, {})", id, handle);
 
-        let request = self.dir_handles.get_mut(&handle);
-        match request {
-            None => {
-                log::warn!("Client requested readdir() on non-existant handle: {handle}");
-                // TODO use SSH_FX_INVALID_HANDLE
-                Err(Self::Error::Failure)
-            }
-            Some(request) => match request {
-                ReadDirRequest::Todo(paths) => {
-                    let mut files: Vec<File> = vec![];
-                    for path in paths {
-                        let path = tr(path)?;
-                        match path.file_name().into_string() {
-                            Ok(path_str) => {
-                                files.push(File::new(
-                                    path_str,
-                                    FileAttributes::from(&tr(path.metadata())?),
-                                ));
-                            }
-                            Err(_) => {
-                                log::error!("Failed to convert file path '{path:?}' to string");
-                            }
-                        }
-                    }
-
-                    *request = ReadDirRequest::Done;
+        // taken out while the batch is read, requests on a handle run one after the other
+        let Some(request) = self.handles().dirs.remove(&handle) else {
+            return tr(Err(invalid_handle(&handle)));
+        };
+        let ReadDirRequest::Todo(mut entries) = request else {
+            self.handles().dirs.insert(handle, ReadDirRequest::Done);
+            return Err(StatusCode::Eof.into());
+        };
 
-                    Ok(Name { id, files })
+        // the directory is read on the blocking pool, and given back for the next batch
+        let (entries, files) = self
+            .unblock(move |_, _| {
+                let mut files = vec![];
+                while files.len() < READDIR_BATCH {
+                    match entries.next() {
+                        Some(Ok(entry)) => files.push(File {
+                            longname: longname(&entry),
+                            filename: entry.name,
+                            attrs: entry.attrs,
+                        }),
+                        // one unreadable entry does not end the listing
+                        Some(Err(err)) => log::warn!("Failed to read a directory entry: {err}"),
+                        None => break,
+                    }
                 }
-                ReadDirRequest::Done => Err(StatusCode::Eof),
-            },
+                Ok((entries, files))
+            })
+            .await?;
+
+        if files.is_empty() {
+            self.handles().dirs.insert(handle, ReadDirRequest::Done);
+            return Err(StatusCode::Eof.into());
         }
+        self.handles()
+            .dirs
+            .insert(handle, ReadDirRequest::Todo(entries));
+        Ok(Name { id, files })
     }
 
     async fn realpath(&mut self, id: u32, mut path: String) -> Result<Name, Self::Error> {
         info!("realpath({}, {})", id, path);
-        // TODO replace std::fs::canonicalize(), it doesn't have the behaviour the RFC wants
+        // TODO replace canonicalize(), it doesn't have the behaviour the RFC wants
 
         if path.is_empty() {
             path = ".".to_string();
         }
 
-        match std::fs::canonicalize(path) {
-            Ok(path) => Ok(Name {
-                id,
-                files: vec![File::new(
-                    path.to_string_lossy().to_string(),
-                    FileAttributes::default(),
-                )],
-            }),
-            Err(err) => {
-                log::error!("error occured in realpath(): {err}");
-                Err(StatusCode::Failure)
-            }
-        }
+        Ok(Name {
+            id,
+            files: vec![File::new(
+                self.real_path(path).await?,
+                FileAttributes::default(),
+            )],
+        })
     }
 
     async fn open(
@@ -259,12 +637,51 @@ impl russh_sftp::server::Handler for SftpSession {
         attrs: FileAttributes,
     ) -> Result<Handle, Self::Error> {
         info!("open({}, {}, {:?}, {:?})", id, filename, pflags, attrs);
+        let writing = pflags.intersects(
+            OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        );
+        self.allow(!writing || self.mode.can_write(), "open for writing")?;
+        self.allow(
+            !pflags.contains(OpenFlags::READ) || self.mode.can_read(),
+            "open for reading",
+        )?;
         let handle = self.new_handle();
 
-        self.file_handles.insert(
+        let mut options = OpenOptions::from(pflags);
+        // drop boxes never overwrite a file
+        options.create_new = writing && !self.mode.can_modify();
+        // only used if the file is created, like OpenSSH
+        options.mode = attrs.permissions.map(|mode| self.allowed_mode(mode));
+        let res = {
+            let filename = filename.clone();
+            self.unblock(move |fs, root| Ok(fs.open(&root.resolve(&filename)?, options)))
+                .await?
+        };
+        if writing {
+            self.audit(Event::SftpOpen {
+                path: &filename,
+                flags: format!("{pflags:?}"),
+                success: res.is_ok(),
+            });
+        }
+
+        let mut handles = self.handles();
+        handles.files.insert(
             handle.clone(),
-            tr(OpenOptions::from(pflags).open(filename))?,
+            OpenFile {
+                path: filename.clone(),
+                file: Arc::from(tr(res)?),
+            },
         );
+        if writing {
+            handles.write_totals.insert(
+                handle.clone(),
+                WriteTotal {
+                    path: filename,
+                    bytes: 0,
+                },
+            );
+        }
         Ok(Handle { id, handle })
     }
 
@@ -276,21 +693,23 @@ impl russh_sftp::server::Handler for SftpSession {
         len: u32,
     ) -> Result<Data, Self::Error> {
         info!("read({}, {}, {}, {})", id, handle, offset, len);
-        if let Some(file) = self.file_handles.get(&handle) {
-            let len = tr(len.try_into())?;
-            let mut data = vec![0u8; len];
-            let read_bytes = tr(file.read_at(&mut data, offset))?;
-            data.resize(read_bytes, 0);
-
-            if read_bytes == 0 {
-                Err(Self::Error::Eof)
-            } else {
-                Ok(Data { id, data })
-            }
+        self.allow(self.mode.can_read(), "read")?;
+        let file = tr(self.file(&handle))?;
+        let len = len.min(MAX_READ as u32) as usize;
+        let data = self
+            .unblock(move |_, _| {
+                let mut data = vec![0u8; len];
+                let read_bytes = tr(file.read_at(&mut data, offset))?;
+                data.resize(read_bytes, 0);
+                Ok(data)
+            })
+            .await?;
+        let read_bytes = data.len();
+
+        if read_bytes == 0 {
+            Err(StatusCode::Eof.into())
         } else {
-            log::warn!("Client requested read() on non-existant handle: {handle}");
-            // TODO use SSH_FX_INVALID_HANDLE
-            Err(Self::Error::Failure)
+            Ok(Data { id, data })
         }
     }
 
@@ -308,20 +727,34 @@ impl russh_sftp::server::Handler for SftpSession {
             offset,
             data.len()
         );
-        if let Some(file) = self.file_handles.get(&handle) {
-            tr(file.write_at(&data, offset))?;
-
-            Ok(status_ok(id))
-        } else {
-            log::warn!("Client requested write() on non-existant handle: {handle}");
-            // TODO use SSH_FX_INVALID_HANDLE
-            Err(Self::Error::Failure)
+        self.allow(self.mode.can_write(), "write")?;
+        let file = match self.file(&handle) {
+            Ok(file) => file,
+            Err(err) => return Ok(status(id, Err(err))),
+        };
+        let len = data.len() as u64;
+        // a short write would silently lose data
+        let res = self
+            .unblock(move |_, _| Ok(file.write_all_at(&data, offset)))
+            .await?;
+        if let (Ok(()), Some(total)) = (&res, self.handles().write_totals.get_mut(&handle)) {
+            total.bytes += len;
         }
+        Ok(status(id, res))
     }
 
     async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
-        tr(std::fs::remove_file(filename))?;
-        Ok(status_ok(id))
+        self.allow(self.mode.can_modify(), "remove")?;
+        let res = {
+            let filename = filename.clone();
+            self.unblock(move |fs, root| Ok(fs.remove_file(&root.resolve_nofollow(&filename)?)))
+                .await?
+        };
+        self.audit(Event::SftpRemove {
+            path: &filename,
+            success: res.is_ok(),
+        });
+        Ok(status(id, res))
     }
 
     async fn rename(
@@ -330,25 +763,55 @@ impl russh_sftp::server::Handler for SftpSession {
         oldpath: String,
         newpath: String,
     ) -> Result<Status, Self::Error> {
-        tr(std::fs::rename(oldpath, newpath))?;
-        Ok(status_ok(id))
+        info!("rename({}, {}, {})", id, oldpath, newpath);
+        let res = self.rename_paths(oldpath, newpath, false).await?;
+        Ok(status(id, res))
     }
 
     async fn readlink(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
         info!("readlink({}, {})", id, path);
-        let real_path = tr(std::fs::read_link(path))?;
-
-        if real_path.exists() {
-            Ok(Name {
-                id,
-                files: vec![File::new(
-                    real_path.to_string_lossy().to_string(),
-                    FileAttributes::from(&tr(real_path.metadata())?),
-                )],
+        self.allow(self.mode.can_read(), "readlink")?;
+        // the target is returned as it is stored, it does not have to exist
+        let target = self
+            .unblock(move |fs, root| {
+                let link = root.resolve_nofollow(&path)?;
+                root.present_link(&tr(fs.read_link(&link))?)
             })
-        } else {
-            Err(StatusCode::NoSuchFile)
-        }
+            .await?;
+
+        Ok(Name {
+            id,
+            files: vec![File::new(target, FileAttributes::default())],
+        })
+    }
+
+    async fn symlink(
+        &mut self,
+        id: u32,
+        linkpath: String,
+        targetpath: String,
+    ) -> Result<Status, Self::Error> {
+        // OpenSSH sends the target first, against the draft. Clients follow OpenSSH, so the
+        // arguments are read in its order
+        let (link, target) = (targetpath, linkpath);
+        info!("symlink({}, {}, {})", id, link, target);
+        // drop boxes only take uploads
+        self.allow(self.mode.can_modify(), "symlink")?;
+        let res = {
+            let (link, target) = (link.clone(), target.clone());
+            self.unblock(move |fs, root| {
+                let link = root.resolve_nofollow(&link)?;
+                let target = root.symlink_target(&link, &target)?;
+                Ok(fs.symlink(&target, &link))
+            })
+            .await?
+        };
+        self.audit(Event::SftpSymlink {
+            link: &link,
+            target: &target,
+            success: res.is_ok(),
+        });
+        Ok(status(id, res))
     }
 
     async fn mkdir(
@@ -358,13 +821,174 @@ impl russh_sftp::server::Handler for SftpSession {
         attrs: FileAttributes,
     ) -> Result<Status, Self::Error> {
         info!("mkdir({}, {}, {:?})", id, path, attrs);
-        tr(std::fs::create_dir(path))?;
-        Ok(status_ok(id))
+        self.allow(self.mode.can_write(), "mkdir")?;
+        let res = {
+            let path = path.clone();
+            let mode = attrs.permissions.map(|mode| self.allowed_mode(mode));
+            self.unblock(move |fs, root| Ok(fs.create_dir(&root.resolve_nofollow(&path)?, mode)))
+                .await?
+        };
+        self.audit(Event::SftpMkdir {
+            path: &path,
+            success: res.is_ok(),
+        });
+        Ok(status(id, res))
     }
 
     async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
         info!("rmdir({}, {})", id, path);
-        tr(std::fs::remove_dir(path))?;
-        Ok(status_ok(id))
+        self.allow(self.mode.can_modify(), "rmdir")?;
+        let res = {
+            let path = path.clone();
+            self.unblock(move |fs, root| Ok(fs.remove_dir(&root.resolve_nofollow(&path)?)))
+                .await?
+        };
+        self.audit(Event::SftpRmdir {
+            path: &path,
+            success: res.is_ok(),
+        });
+        Ok(status(id, res))
+    }
+
+    async fn extended(
+        &mut self,
+        id: u32,
+        request: String,
+        data: Vec<u8>,
+    ) -> Result<Packet, Self::Error> {
+        info!("extended({}, {})", id, request);
+        let mut fields = ExtendedData::new(&data);
+        match request.as_str() {
+            "posix-rename@openssh.com" => {
+                let (oldpath, newpath) = (fields.string()?, fields.string()?);
+                let res = self.rename_paths(oldpath, newpath, true).await?;
+                Ok(Packet::Status(status(id, res)))
+            }
+            "statvfs@openssh.com" => {
+                let path = fields.string()?;
+                let res = self
+                    .unblock(move |fs, root| Ok(fs.statvfs(&root.resolve(&path)?)))
+                    .await?;
+                Ok(extended_reply(id, encode_statvfs(&tr(res)?)))
+            }
+            "fstatvfs@openssh.com" => {
+                let file = tr(self.file(&fields.string()?))?;
+                let res = self.unblock(move |_, _| Ok(file.statvfs())).await?;
+                Ok(extended_reply(id, encode_statvfs(&tr(res)?)))
+            }
+            "hardlink@openssh.com" => {
+                let (oldpath, newpath) = (fields.string()?, fields.string()?);
+                self.allow(self.mode.can_modify(), "hardlink")?;
+                let res = {
+                    let (oldpath, newpath) = (oldpath.clone(), newpath.clone());
+                    self.unblock(move |fs, root| {
+                        Ok(fs.hard_link(
+                            &root.resolve_nofollow(&oldpath)?,
+                            &root.resolve_nofollow(&newpath)?,
+                        ))
+                    })
+                    .await?
+                };
+                self.audit(Event::SftpHardlink {
+                    link: &newpath,
+                    target: &oldpath,
+                    success: res.is_ok(),
+                });
+                Ok(Packet::Status(status(id, res)))
+            }
+            "fsync@openssh.com" => {
+                let res = match self.file(&fields.string()?) {
+                    Ok(file) => self.unblock(move |_, _| Ok(file.sync())).await?,
+                    Err(err) => Err(err),
+                };
+                Ok(Packet::Status(status(id, res)))
+            }
+            "lsetstat@openssh.com" => {
+                let (path, attrs) = (fields.string()?, fields.attrs()?);
+                let res = self.set_attributes(path, attrs, false).await?;
+                Ok(Packet::Status(status(id, res)))
+            }
+            "limits@openssh.com" => {
+                // no limit on open handles
+                let limits = [MAX_PACKET, MAX_READ, MAX_READ, 0];
+                Ok(extended_reply(
+                    id,
+                    limits
+                        .iter()
+                        .flat_map(|limit| limit.to_be_bytes())
+                        .collect(),
+                ))
+            }
+            "expand-path@openssh.com" => {
+                let path = self.root.expand_home(&fields.string()?)?;
+                Ok(Packet::Name(Name {
+                    id,
+                    files: vec![File::new(
+                        self.real_path(path).await?,
+                        FileAttributes::default(),
+                    )],
+                }))
+            }
+            "check-file-name" | "check-file-handle" => {
+                let target = match request.as_str() {
+                    "check-file-name" => CheckFileTarget::Name(fields.string()?),
+                    _ => CheckFileTarget::Handle(tr(self.file(&fields.string()?))?),
+                };
+                let algorithms = fields.string()?;
+                let (start, length, block_size) = (fields.u64()?, fields.u64()?, fields.u32()?);
+                let reply = self
+                    .check_file(target, algorithms, start, length, block_size)
+                    .await?;
+                Ok(extended_reply(id, reply))
+            }
+            "copy-data" => {
+                let (read_handle, read_offset, length) =
+                    (fields.string()?, fields.u64()?, fields.u64()?);
+                let (write_handle, write_offset) = (fields.string()?, fields.u64()?);
+                let res = self
+                    .copy_data(read_handle, read_offset, length, write_handle, write_offset)
+                    .await?;
+                Ok(Packet::Status(status(id, res)))
+            }
+            _ => Err(self.unsupported(&request)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh::vfs::MemoryFs;
+    use russh_sftp::server::Handler;
+
+    fn session(mode: SftpMode) -> SftpSession {
+        let fs: Arc<dyn Filesystem> = Arc::new(MemoryFs::default());
+        let root = SftpRoot::new(fs.clone(), None).unwrap();
+        SftpSession::new(fs, root, mode, Audit::default(), 0)
+    }
+
+    #[tokio::test]
+    async fn invalid_handles_are_named() {
+        let mut session = session(SftpMode::ReadWrite);
+        let err = session.read(1, "7".to_string(), 0, 16).await.unwrap_err();
+        assert_eq!(err.message, "invalid handle 7");
+        let err = session.fstat(2, "7".to_string()).await.unwrap_err();
+        assert_eq!(err.message, "invalid handle 7");
+        let err = session.readdir(3, "7".to_string()).await.unwrap_err();
+        assert_eq!(err.message, "invalid handle 7");
+        let status = session.close(4, "7".to_string()).await.unwrap();
+        assert_eq!(status.error_message, "invalid handle 7");
+    }
+
+    #[tokio::test]
+    async fn errors_carry_a_message() {
+        let mut session = session(SftpMode::DropBox);
+        let err = session.opendir(1, "/".to_string()).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::PermissionDenied);
+        assert_eq!(err.message, "opendir is not allowed");
+
+        let err = session.stat(2, "/missing".to_string()).await.unwrap_err();
+        assert_eq!(err.code, StatusCode::NoSuchFile);
+        assert_ne!(err.message, StatusCode::NoSuchFile.to_string());
     }
 }
@@ -0,0 +1,248 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use russh_sftp::protocol::StatusCode;
+use users::os::unix::UserExt;
+
+use super::sftp_utils::SftpError;
+use super::vfs::{self, Filesystem};
+
+/// Directory SFTP clients are confined to. Clients see it as `/`, and cannot reach anything
+/// outside of it, neither with `..` nor by following symlinks.
+/// Without a root, client paths are used as they are.
+///
+/// Paths are checked, then used: a symlink swapped in between by someone else writing to the
+/// root (another client, or a local user) can still lead out of it. Only give a root to users
+/// that are the sole writers of its directory tree
+pub struct SftpRoot {
+    fs: Arc<dyn Filesystem>,
+    root: Option<PathBuf>,
+}
+
+impl SftpRoot {
+    pub fn new(fs: Arc<dyn Filesystem>, root: Option<&Path>) -> std::io::Result<SftpRoot> {
+        let root = root.map(|root| fs.canonicalize(root)).transpose()?;
+        Ok(SftpRoot { fs, root })
+    }
+
+    fn canonicalize(&self, path: &Path) -> Result<PathBuf, SftpError> {
+        Ok(self.fs.canonicalize(path)?)
+    }
+
+    /// Refuse paths that are not inside the root. `path` must be canonical
+    pub fn check(&self, path: &Path) -> Result<(), SftpError> {
+        match self.root {
+            Some(ref root) if !path.starts_with(root) => {
+                log::warn!(
+                    "Refused SFTP access to {} outside of {}",
+                    path.display(),
+                    root.display()
+                );
+                Err(SftpError::new(
+                    StatusCode::PermissionDenied,
+                    "outside of the SFTP root",
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Path of a client path in the filesystem, without following it if it is a symlink (for lstat, remove, rename...)
+    pub fn resolve_nofollow(&self, path: &str) -> Result<PathBuf, SftpError> {
+        let Some(ref root) = self.root else {
+            return Ok(PathBuf::from(path));
+        };
+
+        let virtual_path = vfs::normalize(Path::new(path));
+        let (Some(parent), Some(name)) = (virtual_path.parent(), virtual_path.file_name()) else {
+            return Ok(root.clone());
+        };
+        // the parent is canonicalized, so symlinks in it cannot lead out of the root
+        let parent = self.canonicalize(&root.join(parent.strip_prefix("/").unwrap_or(parent)))?;
+        self.check(&parent)?;
+        Ok(parent.join(name))
+    }
+
+    /// Path of a client path in the filesystem, following it if it is a symlink
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, SftpError> {
+        let real = self.resolve_nofollow(path)?;
+        if self.root.is_none() {
+            return Ok(real);
+        }
+
+        match self.fs.symlink_metadata(&real) {
+            Ok(attrs) if vfs::is_symlink(&attrs) => {
+                // dangling symlinks are refused too, creating a file through one could escape the root
+                let target = self.canonicalize(&real)?;
+                self.check(&target)?;
+                Ok(target)
+            }
+            _ => Ok(real),
+        }
+    }
+
+    /// Target to store for a symlink a client creates at `link`. Absolute targets are client
+    /// paths, relative ones are kept as they are. Targets leading outside of the root are refused
+    pub fn symlink_target(&self, link: &Path, target: &str) -> Result<PathBuf, SftpError> {
+        let Some(ref root) = self.root else {
+            return Ok(PathBuf::from(target));
+        };
+
+        let target = Path::new(target);
+        if target.is_absolute() {
+            let target = vfs::normalize(target);
+            return Ok(root.join(target.strip_prefix("/").unwrap_or(&target)));
+        }
+        // symlinks in the path are checked when the link is followed
+        let lexical = vfs::normalize(&link.parent().unwrap_or(link).join(target));
+        self.check(&lexical)?;
+        Ok(target.to_path_buf())
+    }
+
+    /// Target of a symlink as the client sees it. Relative targets are shown as they are
+    pub fn present_link(&self, target: &Path) -> Result<String, SftpError> {
+        if self.root.is_none() || target.is_relative() {
+            return Ok(target.to_string_lossy().to_string());
+        }
+        self.check(target)?;
+        Ok(self.present(target))
+    }
+
+    /// Expand `~` and `~user` at the start of a client path. Confined clients only have `~`,
+    /// which is the root
+    pub fn expand_home(&self, path: &str) -> Result<String, SftpError> {
+        let Some(rest) = path.strip_prefix('~') else {
+            return Ok(path.to_string());
+        };
+        let (name, rest) = rest.split_once('/').unwrap_or((rest, ""));
+        let home = match (&self.root, name) {
+            (Some(_), "") => PathBuf::from("/"),
+            (Some(_), _) => {
+                return Err(SftpError::new(
+                    StatusCode::PermissionDenied,
+                    "outside of the SFTP root",
+                ))
+            }
+            (None, "") => users::get_user_by_uid(users::get_current_uid())
+                .ok_or(StatusCode::NoSuchFile)?
+                .home_dir()
+                .to_path_buf(),
+            (None, name) => users::get_user_by_name(name)
+                .ok_or_else(|| SftpError::new(StatusCode::NoSuchFile, format!("no user {name}")))?
+                .home_dir()
+                .to_path_buf(),
+        };
+        Ok(home.join(rest).to_string_lossy().to_string())
+    }
+
+    /// Path to show to the client for a path of the filesystem
+    pub fn present(&self, path: &Path) -> String {
+        let Some(ref root) = self.root else {
+            return path.to_string_lossy().to_string();
+        };
+
+        match path.strip_prefix(root) {
+            Ok(relative) => Path::new("/").join(relative).to_string_lossy().to_string(),
+            Err(_) => "/".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink};
+
+    use super::*;
+    use crate::ssh::vfs::LocalFs;
+
+    /// A root with a `dir` directory, next to a directory `outside` holding a `secret` file
+    fn setup(name: &str) -> (PathBuf, SftpRoot) {
+        let dir = std::env::temp_dir().join(format!("quickssh-root-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/dir")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::write(dir.join("outside/secret"), "secret").unwrap();
+        let dir = fs::canonicalize(dir).unwrap();
+        let root = SftpRoot::new(Arc::new(LocalFs), Some(&dir.join("root"))).unwrap();
+        (dir, root)
+    }
+
+    #[test]
+    fn parent_dir_stays_in_root() {
+        let (dir, root) = setup("parent");
+        assert_eq!(root.resolve("..").ok(), Some(dir.join("root")));
+        assert_eq!(root.resolve("/../../dir").ok(), Some(dir.join("root/dir")));
+        assert_eq!(
+            root.resolve_nofollow("/dir/../../outside").ok(),
+            Some(dir.join("root/outside"))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn symlinks_stay_in_root() {
+        let (dir, root) = setup("symlinks");
+        symlink(dir.join("outside"), dir.join("root/out")).unwrap();
+        symlink("../outside/secret", dir.join("root/secret")).unwrap();
+        symlink("dir", dir.join("root/inside")).unwrap();
+
+        // in the parent
+        assert!(matches!(
+            root.resolve("/out/secret"),
+            Err(SftpError {
+                code: StatusCode::PermissionDenied,
+                ..
+            })
+        ));
+        // the file itself
+        assert!(matches!(
+            root.resolve("/secret"),
+            Err(SftpError {
+                code: StatusCode::PermissionDenied,
+                ..
+            })
+        ));
+        // the link can still be removed
+        assert_eq!(
+            root.resolve_nofollow("/secret").ok(),
+            Some(dir.join("root/secret"))
+        );
+        assert_eq!(root.resolve("/inside").ok(), Some(dir.join("root/dir")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dangling_symlink() {
+        let (dir, root) = setup("dangling");
+        symlink(dir.join("outside/new"), dir.join("root/new")).unwrap();
+        symlink("dir/new", dir.join("root/new_inside")).unwrap();
+
+        // refused wherever they point, creating a file through one could escape the root
+        assert!(root.resolve("/new").is_err());
+        assert!(root.resolve("/new_inside").is_err());
+        assert!(root
+            .symlink_target(&dir.join("root/link"), "../outside")
+            .is_err());
+        assert_eq!(
+            root.symlink_target(&dir.join("root/link"), "/dir").ok(),
+            Some(dir.join("root/dir"))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn present() {
+        let (dir, root) = setup("present");
+        assert_eq!(root.present(&dir.join("root")), "/");
+        assert_eq!(root.present(&dir.join("root/dir/file")), "/dir/file");
+        assert_eq!(root.present(&dir.join("outside")), "/");
+        assert_eq!(
+            root.present_link(Path::new("../x")).ok().as_deref(),
+            Some("../x")
+        );
+        assert!(root.present_link(&dir.join("outside/secret")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
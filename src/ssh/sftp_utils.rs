@@ -1,10 +1,64 @@
-use russh_sftp::protocol::FileAttributes;
+use super::vfs::{DirEntry, StatVfs};
+use russh_sftp::protocol::{FileAttributes, StatusCode};
 use std::{
     ffi::CString,
     fs::{Metadata, OpenOptions},
-    os::unix::fs::{MetadataExt, PermissionsExt},
+    io::{self, ErrorKind},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+/// Status code of a failed filesystem call. SFTP v3 only has a few codes: the errors of later
+/// versions (file already exists, directory not empty, no space left...) are failures, told
+/// apart by the message of the status
+pub fn status_code(err: &io::Error) -> StatusCode {
+    match err.kind() {
+        ErrorKind::NotFound => StatusCode::NoSuchFile,
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => StatusCode::PermissionDenied,
+        ErrorKind::Unsupported => StatusCode::OpUnsupported,
+        _ => StatusCode::Failure,
+    }
+}
+
+/// Error reply of a request: a status code, and a message telling the client what went wrong.
+/// The message is all that tells apart the failures of SFTP v3
+#[derive(Debug)]
+pub struct SftpError {
+    pub code: StatusCode,
+    pub message: String,
+}
+
+impl SftpError {
+    pub fn new(code: StatusCode, message: impl Into<String>) -> Self {
+        SftpError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<StatusCode> for SftpError {
+    fn from(code: StatusCode) -> Self {
+        SftpError::new(code, code.to_string())
+    }
+}
+
+impl From<io::Error> for SftpError {
+    fn from(err: io::Error) -> Self {
+        SftpError::new(status_code(&err), err.to_string())
+    }
+}
+
+impl From<SftpError> for StatusCode {
+    fn from(err: SftpError) -> Self {
+        err.code
+    }
+}
+
 fn timeval_secs(secs: i64) -> libc::timeval {
     libc::timeval {
         tv_sec: secs,
@@ -12,14 +66,14 @@ fn timeval_secs(secs: i64) -> libc::timeval {
     }
 }
 
-pub fn apply_file_attributes(path: String, attrs: &FileAttributes) -> anyhow::Result<()> {
+pub fn apply_file_attributes(path: &Path, attrs: &FileAttributes) -> std::io::Result<()> {
     if let Some(size) = attrs.size {
-        let file = OpenOptions::new().read(true).write(true).open(&path)?;
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
         file.set_len(size)?;
     }
 
-    let md = std::fs::metadata(&path)?;
-    let cpath = CString::new(path.as_bytes())?;
+    let md = std::fs::metadata(path)?;
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
 
     // modify owner/group
     {
@@ -51,10 +105,10 @@ pub fn apply_file_attributes(path: String, attrs: &FileAttributes) -> anyhow::Re
     let mut times = (md.atime(), md.mtime());
     unsafe {
         if let Some(atime) = attrs.atime {
-            times.0 = atime.try_into()?;
+            times.0 = atime.into();
         }
         if let Some(mtime) = attrs.mtime {
-            times.1 = mtime.try_into()?;
+            times.1 = mtime.into();
         }
         if times != (md.atime(), md.mtime()) {
             libc::utimes(
@@ -67,19 +121,179 @@ pub fn apply_file_attributes(path: String, attrs: &FileAttributes) -> anyhow::Re
 }
 
 pub fn metadata_to_file_attributes(md: &Metadata) -> FileAttributes {
-    let user = users::get_user_by_uid(md.uid())
-        .unwrap()
-        .name()
-        .to_string_lossy()
-        .to_string();
-    let group = users::get_group_by_gid(md.gid())
-        .unwrap()
-        .name()
-        .to_string_lossy()
-        .to_string();
+    // ids without a name (deleted users, files from other systems) are shown as numbers
+    let user = users::get_user_by_uid(md.uid()).map_or_else(
+        || md.uid().to_string(),
+        |user| user.name().to_string_lossy().to_string(),
+    );
+    let group = users::get_group_by_gid(md.gid()).map_or_else(
+        || md.gid().to_string(),
+        |group| group.name().to_string_lossy().to_string(),
+    );
     let mut attrs = FileAttributes::from(md);
     attrs.user = Some(user);
     attrs.group = Some(group);
 
     attrs
 }
+
+/// `ls -l` line of a directory entry, the way OpenSSH shows it
+pub fn longname(entry: &DirEntry) -> String {
+    let attrs = &entry.attrs;
+    let user = attrs
+        .user
+        .clone()
+        .or_else(|| attrs.uid.map(|uid| uid.to_string()))
+        .unwrap_or_default();
+    let group = attrs
+        .group
+        .clone()
+        .or_else(|| attrs.gid.map(|gid| gid.to_string()))
+        .unwrap_or_default();
+    format!(
+        "{} {:>3} {:<8} {:<8} {:>8} {} {}",
+        mode_string(attrs.permissions.unwrap_or(0)),
+        entry.nlink,
+        user,
+        group,
+        attrs.size.unwrap_or(0),
+        format_mtime(attrs.mtime.unwrap_or(0)),
+        entry.name
+    )
+}
+
+/// Like `drwxr-xr-x`
+fn mode_string(mode: u32) -> String {
+    let mut string = String::from(match mode & libc::S_IFMT {
+        libc::S_IFDIR => 'd',
+        libc::S_IFLNK => 'l',
+        libc::S_IFCHR => 'c',
+        libc::S_IFBLK => 'b',
+        libc::S_IFIFO => 'p',
+        libc::S_IFSOCK => 's',
+        _ => '-',
+    });
+    for (shift, special, letter) in [
+        (6, libc::S_ISUID, 's'),
+        (3, libc::S_ISGID, 's'),
+        (0, libc::S_ISVTX, 't'),
+    ] {
+        let bits = (mode >> shift) & 0o7;
+        string.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        string.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        string.push(match (mode & special != 0, bits & 0o1 != 0) {
+            (true, true) => letter,
+            (true, false) => letter.to_ascii_uppercase(),
+            (false, true) => 'x',
+            (false, false) => '-',
+        });
+    }
+    string
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Modification date in local time, with the year instead of the time when it is more
+/// than 6 months old or in the future, like `ls`
+fn format_mtime(mtime: u32) -> String {
+    let time = mtime as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return "??? ?? ??:??".to_string();
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let month = MONTHS[tm.tm_mon.clamp(0, 11) as usize];
+    let mtime = mtime as i64;
+    if now - 365 * 24 * 3600 / 2 < mtime && mtime <= now {
+        format!(
+            "{month} {:>2} {:02}:{:02}",
+            tm.tm_mday, tm.tm_hour, tm.tm_min
+        )
+    } else {
+        format!("{month} {:>2}  {}", tm.tm_mday, tm.tm_year + 1900)
+    }
+}
+
+/// Reads the fields of an extended request, encoded like the rest of SFTP
+pub struct ExtendedData<'a>(&'a [u8]);
+
+impl<'a> ExtendedData<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        ExtendedData(data)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], StatusCode> {
+        if self.0.len() < len {
+            return Err(StatusCode::BadMessage);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StatusCode> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StatusCode> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn string(&mut self) -> Result<String, StatusCode> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).to_string())
+    }
+
+    pub fn attrs(&mut self) -> Result<FileAttributes, StatusCode> {
+        let flags = self.u32()?;
+        let mut attrs = FileAttributes::default();
+        if flags & 0x1 != 0 {
+            attrs.size = Some(self.u64()?);
+        }
+        if flags & 0x2 != 0 {
+            attrs.uid = Some(self.u32()?);
+            attrs.gid = Some(self.u32()?);
+        }
+        if flags & 0x4 != 0 {
+            attrs.permissions = Some(self.u32()?);
+        }
+        if flags & 0x8 != 0 {
+            attrs.atime = Some(self.u32()?);
+            attrs.mtime = Some(self.u32()?);
+        }
+        // extended attributes are not supported, they are skipped
+        if flags & 0x80000000 != 0 {
+            for _ in 0..self.u32()? {
+                self.string()?;
+                self.string()?;
+            }
+        }
+        Ok(attrs)
+    }
+}
+
+/// Reply of `statvfs@openssh.com`
+pub fn encode_statvfs(st: &StatVfs) -> Vec<u8> {
+    [
+        st.block_size,
+        st.fragment_size,
+        st.blocks,
+        st.blocks_free,
+        st.blocks_available,
+        st.files,
+        st.files_free,
+        st.files_available,
+        st.fsid,
+        st.flags,
+        st.name_max,
+    ]
+    .iter()
+    .flat_map(|value| value.to_be_bytes())
+    .collect()
+}
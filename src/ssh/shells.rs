@@ -0,0 +1,399 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use pty_process::OwnedWritePty;
+use russh::{server::Handle, ChannelId, CryptoVec};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use super::{recording::Recorder, registry::Traffic};
+
+/// Recent output of a shell replayed to a viewer attaching to it
+const SCROLLBACK_SIZE: usize = 64 * 1024;
+
+/// A channel showing a shell
+pub struct Viewer {
+    pub connection: usize,
+    pub channel: ChannelId,
+    pub handle: Handle,
+    pub read_only: bool,
+    pub cols: u32,
+    pub rows: u32,
+    pub traffic: Option<Arc<Traffic>>,
+}
+
+/// What the control socket reports about a shell
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShellInfo {
+    pub id: usize,
+    pub user: String,
+    pub pid: Option<u32>,
+    /// Connection ids of the viewers, with whether they are read-only
+    pub viewers: Vec<(usize, bool)>,
+}
+
+/// A shell process and its pty, that several channels can be attached to
+pub struct SharedShell {
+    pub id: usize,
+    pub user: String,
+    pub pid: Option<u32>,
+    writer: tokio::sync::Mutex<OwnedWritePty>,
+    viewers: Mutex<Vec<Viewer>>,
+    scrollback: Mutex<VecDeque<u8>>,
+    // held while output is sent, so a new viewer gets the scrollback before any newer output
+    fanout: tokio::sync::Mutex<()>,
+    // bumped on every attach, so a pending hangup knows the shell was reattached meanwhile
+    generation: AtomicUsize,
+    recorder: Option<Recorder>,
+}
+
+impl SharedShell {
+    pub fn new(
+        id: usize,
+        user: String,
+        pid: Option<u32>,
+        writer: OwnedWritePty,
+        recorder: Option<Recorder>,
+    ) -> Self {
+        SharedShell {
+            id,
+            user,
+            pid,
+            writer: tokio::sync::Mutex::new(writer),
+            viewers: Mutex::new(vec![]),
+            scrollback: Mutex::new(VecDeque::with_capacity(SCROLLBACK_SIZE)),
+            fanout: tokio::sync::Mutex::new(()),
+            generation: AtomicUsize::new(0),
+            recorder,
+        }
+    }
+
+    /// Send output of the shell to every viewer. Viewers whose connection is gone are dropped,
+    /// in which case this returns true
+    pub async fn output(&self, data: &[u8]) -> bool {
+        let fanout = self.fanout.lock().await;
+        if let Some(ref recorder) = self.recorder {
+            recorder.output(data);
+        }
+        {
+            let mut scrollback = self.scrollback.lock().unwrap();
+            scrollback.extend(data);
+            let overflow = scrollback.len().saturating_sub(SCROLLBACK_SIZE);
+            scrollback.drain(..overflow);
+        }
+
+        let targets: Vec<(usize, ChannelId, Handle, Option<Arc<Traffic>>)> = self
+            .viewers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|viewer| {
+                (
+                    viewer.connection,
+                    viewer.channel,
+                    viewer.handle.clone(),
+                    viewer.traffic.clone(),
+                )
+            })
+            .collect();
+        let mut gone = vec![];
+        for (connection, channel, handle, traffic) in targets {
+            if let Some(traffic) = traffic {
+                traffic.add_sent(data.len());
+            }
+            if handle
+                .data(channel, CryptoVec::from_slice(data))
+                .await
+                .is_err()
+            {
+                gone.push((connection, channel));
+            }
+        }
+        drop(fanout);
+
+        if gone.is_empty() {
+            return false;
+        }
+        self.viewers
+            .lock()
+            .unwrap()
+            .retain(|viewer| !gone.contains(&(viewer.connection, viewer.channel)));
+        self.arbitrate_size().await;
+        true
+    }
+
+    /// Send SIGHUP to the shell process, like a terminal hangup would
+    pub fn hangup(&self) {
+        if let Some(pid) = self.pid {
+            log::debug!("Sending SIGHUP to shell process {pid}");
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGHUP);
+            }
+        }
+    }
+
+    /// Hang up the shell if nobody views it anymore, after `grace` if set so the user can reattach
+    pub fn release(self: &Arc<Self>, grace: Option<Duration>) {
+        if self.has_viewers() {
+            return;
+        }
+        let Some(grace) = grace else {
+            self.hangup();
+            return;
+        };
+
+        log::info!(
+            "Shell {} detached, keeping it for {}s",
+            self.id,
+            grace.as_secs()
+        );
+        let generation = self.generation.load(Ordering::SeqCst);
+        let shell = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            if !shell.has_viewers() && shell.generation.load(Ordering::SeqCst) == generation {
+                log::info!("Shell {} was not reattached, hanging it up", shell.id);
+                shell.hangup();
+            }
+        });
+    }
+
+    pub async fn input(&self, data: &[u8]) -> std::io::Result<()> {
+        if let Some(ref recorder) = self.recorder {
+            recorder.input(data);
+        }
+        self.writer.lock().await.write_all(data).await
+    }
+
+    pub fn is_read_only(&self, connection: usize, channel: ChannelId) -> bool {
+        self.viewers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|viewer| viewer.connection == connection && viewer.channel == channel)
+            .map_or(true, |viewer| viewer.read_only)
+    }
+
+    pub fn viewers(&self) -> Vec<(usize, ChannelId, Handle)> {
+        self.viewers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|viewer| (viewer.connection, viewer.channel, viewer.handle.clone()))
+            .collect()
+    }
+
+    pub fn has_viewers(&self) -> bool {
+        !self.viewers.lock().unwrap().is_empty()
+    }
+
+    pub async fn resize(&self, connection: usize, channel: ChannelId, cols: u32, rows: u32) {
+        if let Some(viewer) = self
+            .viewers
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|viewer| viewer.connection == connection && viewer.channel == channel)
+        {
+            viewer.cols = cols;
+            viewer.rows = rows;
+        }
+        self.arbitrate_size().await;
+    }
+
+    /// The pty gets the smallest size of the read-write viewers, so the shell fits in every terminal typing in it
+    async fn arbitrate_size(&self) {
+        let size = {
+            let viewers = self.viewers.lock().unwrap();
+            let writers: Vec<&Viewer> = viewers.iter().filter(|viewer| !viewer.read_only).collect();
+            let candidates = if writers.is_empty() {
+                viewers.iter().collect()
+            } else {
+                writers
+            };
+            candidates
+                .iter()
+                .map(|viewer| viewer.cols)
+                .min()
+                .zip(candidates.iter().map(|viewer| viewer.rows).min())
+        };
+
+        if let Some((cols, rows)) = size {
+            if let Err(e) = self
+                .writer
+                .lock()
+                .await
+                .resize(pty_process::Size::new(rows as u16, cols as u16))
+            {
+                log::error!("pty.resize failed: {:?}", e);
+            }
+            if let Some(ref recorder) = self.recorder {
+                recorder.resize(cols, rows);
+            }
+        }
+    }
+}
+
+/// The running shells, and the channels attached to them
+#[derive(Clone, Default)]
+pub struct Shells {
+    shells: Arc<Mutex<HashMap<usize, Arc<SharedShell>>>>,
+    attachments: Arc<Mutex<HashMap<(usize, ChannelId), Arc<SharedShell>>>>,
+    next_id: Arc<AtomicUsize>,
+    // how long a shell nobody views is kept before being hung up
+    detach_grace: Option<Duration>,
+}
+
+impl Shells {
+    pub fn new(detach_grace: Option<Duration>) -> Self {
+        Shells {
+            detach_grace,
+            ..Default::default()
+        }
+    }
+
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn detach_grace(&self) -> Option<Duration> {
+        self.detach_grace
+    }
+
+    pub fn insert(&self, shell: Arc<SharedShell>) {
+        self.shells.lock().unwrap().insert(shell.id, shell);
+    }
+
+    pub fn remove(&self, id: usize) {
+        self.shells.lock().unwrap().remove(&id);
+        self.attachments
+            .lock()
+            .unwrap()
+            .retain(|_, shell| shell.id != id);
+    }
+
+    /// Processes of every running shell. Detached shells belong to no connection anymore, this is
+    /// the only place they can be found
+    pub fn pids(&self) -> Vec<u32> {
+        self.shells
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|shell| shell.pid)
+            .collect()
+    }
+
+    /// Whether some shells nobody views are kept for a reattach
+    pub fn has_detached(&self) -> bool {
+        self.shells
+            .lock()
+            .unwrap()
+            .values()
+            .any(|shell| !shell.has_viewers())
+    }
+
+    pub fn get(&self, id: usize) -> Option<Arc<SharedShell>> {
+        self.shells.lock().unwrap().get(&id).cloned()
+    }
+
+    /// The shell a channel is attached to
+    pub fn find(&self, connection: usize, channel: ChannelId) -> Option<Arc<SharedShell>> {
+        self.attachments
+            .lock()
+            .unwrap()
+            .get(&(connection, channel))
+            .cloned()
+    }
+
+    /// Attach a channel to a shell, replaying the recent output to it first
+    pub async fn attach(&self, shell: &Arc<SharedShell>, viewer: Viewer) {
+        let fanout = shell.fanout.lock().await;
+        let scrollback: Vec<u8> = shell.scrollback.lock().unwrap().iter().copied().collect();
+        if !scrollback.is_empty() {
+            if let Some(ref traffic) = viewer.traffic {
+                traffic.add_sent(scrollback.len());
+            }
+            let _ = viewer
+                .handle
+                .data(viewer.channel, CryptoVec::from_slice(&scrollback))
+                .await;
+        }
+
+        self.attachments
+            .lock()
+            .unwrap()
+            .insert((viewer.connection, viewer.channel), shell.clone());
+        shell.generation.fetch_add(1, Ordering::SeqCst);
+        shell.viewers.lock().unwrap().push(viewer);
+        drop(fanout);
+        shell.arbitrate_size().await;
+    }
+
+    /// Detach a channel from its shell, returning the shell. The shell is released if it was its last viewer
+    pub async fn detach(&self, connection: usize, channel: ChannelId) -> Option<Arc<SharedShell>> {
+        let shell = self
+            .attachments
+            .lock()
+            .unwrap()
+            .remove(&(connection, channel))?;
+        shell
+            .viewers
+            .lock()
+            .unwrap()
+            .retain(|viewer| !(viewer.connection == connection && viewer.channel == channel));
+        shell.arbitrate_size().await;
+        shell.release(self.detach_grace);
+        Some(shell)
+    }
+
+    /// Detach every channel of a connection, for connections that dropped without closing their channels
+    pub fn detach_connection(&self, connection: usize) {
+        let channels: Vec<ChannelId> = self
+            .attachments
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(id, _)| *id == connection)
+            .map(|(_, channel)| *channel)
+            .collect();
+        if channels.is_empty() {
+            return;
+        }
+
+        let shells = self.clone();
+        tokio::spawn(async move {
+            for channel in channels {
+                shells.detach(connection, channel).await;
+            }
+        });
+    }
+
+    pub fn list(&self) -> Vec<ShellInfo> {
+        let mut shells: Vec<ShellInfo> = self
+            .shells
+            .lock()
+            .unwrap()
+            .values()
+            .map(|shell| ShellInfo {
+                id: shell.id,
+                user: shell.user.clone(),
+                pid: shell.pid,
+                viewers: shell
+                    .viewers
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|viewer| (viewer.connection, viewer.read_only))
+                    .collect(),
+            })
+            .collect();
+        shells.sort_by_key(|shell| shell.id);
+        shells
+    }
+}
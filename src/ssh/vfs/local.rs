@@ -0,0 +1,229 @@
+use std::{
+    ffi::CString,
+    fs::{DirBuilder, File, FileTimes, Permissions},
+    io::{self, ErrorKind},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use russh_sftp::protocol::FileAttributes;
+
+use super::{DirEntry, FileHandle, Filesystem, OpenOptions, ReadDir, StatVfs};
+use crate::ssh::sftp_utils::{apply_file_attributes, metadata_to_file_attributes};
+
+/// The filesystem of the server, as the user running it sees it
+pub struct LocalFs;
+
+// the field types differ between platforms
+#[allow(clippy::unnecessary_cast)]
+impl From<libc::statvfs> for StatVfs {
+    fn from(st: libc::statvfs) -> Self {
+        StatVfs {
+            block_size: st.f_bsize as u64,
+            fragment_size: st.f_frsize as u64,
+            blocks: st.f_blocks as u64,
+            blocks_free: st.f_bfree as u64,
+            blocks_available: st.f_bavail as u64,
+            files: st.f_files as u64,
+            files_free: st.f_ffree as u64,
+            files_available: st.f_favail as u64,
+            fsid: st.f_fsid as u64,
+            // the values of the extension are the ones of Linux
+            flags: (st.f_flag & (libc::ST_RDONLY | libc::ST_NOSUID)) as u64,
+            name_max: st.f_namemax as u64,
+        }
+    }
+}
+
+fn timespec(time: Option<u32>) -> libc::timespec {
+    match time {
+        Some(secs) => libc::timespec {
+            tv_sec: secs.into(),
+            tv_nsec: 0,
+        },
+        None => libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+    }
+}
+
+impl FileHandle for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        FileExt::read_at(self, buf, offset)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<usize> {
+        FileExt::write_at(self, data, offset)
+    }
+
+    fn metadata(&self) -> io::Result<FileAttributes> {
+        Ok(metadata_to_file_attributes(&File::metadata(self)?))
+    }
+
+    fn set_attributes(&self, attrs: &FileAttributes) -> io::Result<()> {
+        if let Some(size) = attrs.size {
+            self.set_len(size)?;
+        }
+        if let Some(permissions) = attrs.permissions {
+            self.set_permissions(Permissions::from_mode(permissions & 0o7777))?;
+        }
+        if attrs.uid.is_some() || attrs.gid.is_some() {
+            std::os::unix::fs::fchown(self, attrs.uid, attrs.gid)?;
+        }
+        let mut times = FileTimes::new();
+        if let Some(atime) = attrs.atime {
+            times = times.set_accessed(UNIX_EPOCH + Duration::from_secs(atime.into()));
+        }
+        if let Some(mtime) = attrs.mtime {
+            times = times.set_modified(UNIX_EPOCH + Duration::from_secs(mtime.into()));
+        }
+        self.set_times(times)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn statvfs(&self) -> io::Result<StatVfs> {
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatvfs(self.as_raw_fd(), &mut st) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(st.into())
+    }
+}
+
+impl Filesystem for LocalFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileAttributes> {
+        Ok(metadata_to_file_attributes(&std::fs::metadata(path)?))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileAttributes> {
+        Ok(metadata_to_file_attributes(&std::fs::symlink_metadata(
+            path,
+        )?))
+    }
+
+    fn set_attributes(&self, path: &Path, attrs: &FileAttributes) -> io::Result<()> {
+        apply_file_attributes(path, attrs)
+    }
+
+    fn set_symlink_attributes(&self, path: &Path, attrs: &FileAttributes) -> io::Result<()> {
+        if !std::fs::symlink_metadata(path)?.is_symlink() {
+            return apply_file_attributes(path, attrs);
+        }
+        // Linux has no size or permissions for symlinks themselves
+        if attrs.size.is_some() || attrs.permissions.is_some() {
+            return Err(ErrorKind::Unsupported.into());
+        }
+        if attrs.uid.is_some() || attrs.gid.is_some() {
+            std::os::unix::fs::lchown(path, attrs.uid, attrs.gid)?;
+        }
+        if attrs.atime.is_some() || attrs.mtime.is_some() {
+            let cpath = CString::new(path.as_os_str().as_bytes())?;
+            let times = [timespec(attrs.atime), timespec(attrs.mtime)];
+            let res = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    cpath.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if res != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        let entries = std::fs::read_dir(path)?.filter_map(|entry| {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            // SFTP v3 clients cannot send such a name back, a lossy one would name another file
+            let Ok(name) = entry.file_name().into_string() else {
+                log::warn!(
+                    "Skipped {:?} in a directory listing: its name is not UTF-8",
+                    entry.path()
+                );
+                return None;
+            };
+            // does not follow symlinks. An entry removed since the listing is still shown, without attributes
+            let (attrs, nlink) = match entry.metadata() {
+                Ok(md) => (metadata_to_file_attributes(&md), md.nlink()),
+                Err(err) => {
+                    log::warn!("Failed to read the attributes of {:?}: {err}", entry.path());
+                    (FileAttributes::default(), 1)
+                }
+            };
+            Some(Ok(DirEntry { name, attrs, nlink }))
+        });
+        Ok(Box::new(entries))
+    }
+
+    fn open(&self, path: &Path, options: OpenOptions) -> io::Result<Box<dyn FileHandle>> {
+        let mut builder = std::fs::OpenOptions::new();
+        if let Some(mode) = options.mode {
+            builder.mode(mode);
+        }
+        let file = builder
+            .read(options.read)
+            .write(options.write)
+            .append(options.append)
+            .create(options.create)
+            .truncate(options.truncate)
+            .create_new(options.create_new)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn create_dir(&self, path: &Path, mode: Option<u32>) -> io::Result<()> {
+        DirBuilder::new().mode(mode.unwrap_or(0o777)).create(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir(path)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::read_link(path)
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        std::fs::hard_link(original, link)
+    }
+
+    fn statvfs(&self, path: &Path) -> io::Result<StatVfs> {
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(cpath.as_ptr(), &mut st) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(st.into())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::canonicalize(path)
+    }
+}
@@ -0,0 +1,509 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use russh_sftp::protocol::FileAttributes;
+
+use super::{normalize, DirEntry, FileHandle, Filesystem, OpenOptions, ReadDir, StatVfs};
+
+/// Content a memory filesystem holds at most, all files together
+const DEFAULT_LIMIT: u64 = 1 << 30;
+
+/// A filesystem kept in memory, lost when the server stops. Has no symlinks
+pub struct MemoryFs {
+    // every file and directory, by normalized path
+    nodes: Mutex<HashMap<PathBuf, Arc<Mutex<Node>>>>,
+    usage: Arc<Usage>,
+}
+
+/// Bytes of file content held by a filesystem, shared with its nodes
+struct Usage {
+    used: AtomicU64,
+    limit: u64,
+}
+
+impl Usage {
+    /// Account for a file going from `old` to `new` bytes. Growing past the limit fails
+    fn resize(&self, old: usize, new: usize) -> io::Result<()> {
+        if new <= old {
+            self.used.fetch_sub((old - new) as u64, Ordering::Relaxed);
+            return Ok(());
+        }
+        let grow = (new - old) as u64;
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(grow).filter(|&used| used <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|_| ErrorKind::StorageFull.into())
+    }
+}
+
+struct Node {
+    // None for directories
+    content: Option<Vec<u8>>,
+    mode: u32,
+    atime: u32,
+    mtime: u32,
+    usage: Arc<Usage>,
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+impl Node {
+    fn new(content: Option<Vec<u8>>, mode: u32, usage: &Arc<Usage>) -> Arc<Mutex<Node>> {
+        let time = now();
+        Arc::new(Mutex::new(Node {
+            content,
+            mode,
+            atime: time,
+            mtime: time,
+            usage: usage.clone(),
+        }))
+    }
+
+    /// Change the size of a file, within the limit of the filesystem
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        let Some(ref mut content) = self.content else {
+            return Err(ErrorKind::IsADirectory.into());
+        };
+        let size = usize::try_from(size).map_err(|_| io::Error::from(ErrorKind::FileTooLarge))?;
+        self.usage.resize(content.len(), size)?;
+        content.resize(size, 0);
+        Ok(())
+    }
+
+    fn attrs(&self) -> FileAttributes {
+        let kind = match self.content {
+            Some(_) => libc::S_IFREG,
+            None => libc::S_IFDIR,
+        };
+        FileAttributes {
+            size: Some(
+                self.content
+                    .as_ref()
+                    .map_or(0, |content| content.len() as u64),
+            ),
+            uid: Some(unsafe { libc::getuid() }),
+            gid: Some(unsafe { libc::getgid() }),
+            permissions: Some(kind | self.mode),
+            atime: Some(self.atime),
+            mtime: Some(self.mtime),
+            ..Default::default()
+        }
+    }
+
+    fn set_attributes(&mut self, attrs: &FileAttributes) -> io::Result<()> {
+        if let Some(size) = attrs.size {
+            self.resize(size)?;
+        }
+        if let Some(permissions) = attrs.permissions {
+            self.mode = permissions & 0o7777;
+        }
+        if let Some(atime) = attrs.atime {
+            self.atime = atime;
+        }
+        if let Some(mtime) = attrs.mtime {
+            self.mtime = mtime;
+        }
+        // there is no owner to change
+        Ok(())
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if let Some(ref content) = self.content {
+            let _ = self.usage.resize(content.len(), 0);
+        }
+    }
+}
+
+struct MemoryHandle {
+    node: Arc<Mutex<Node>>,
+    options: OpenOptions,
+}
+
+impl FileHandle for MemoryHandle {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        if !self.options.read {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        let mut node = self.node.lock().unwrap();
+        node.atime = now();
+        let content = node.content.as_deref().unwrap_or_default();
+        let start = (offset as usize).min(content.len());
+        let len = buf.len().min(content.len() - start);
+        buf[..len].copy_from_slice(&content[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<usize> {
+        if !self.options.write && !self.options.append {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        let mut node = self.node.lock().unwrap();
+        let len = node.content.as_ref().map_or(0, Vec::len) as u64;
+        let start = if self.options.append { len } else { offset };
+        let end = start
+            .checked_add(data.len() as u64)
+            .ok_or(ErrorKind::FileTooLarge)?;
+        if len < end {
+            node.resize(end)?;
+        }
+        node.mtime = now();
+        let content = node.content.as_mut().ok_or(ErrorKind::IsADirectory)?;
+        content[start as usize..end as usize].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn metadata(&self) -> io::Result<FileAttributes> {
+        Ok(self.node.lock().unwrap().attrs())
+    }
+
+    fn set_attributes(&self, attrs: &FileAttributes) -> io::Result<()> {
+        self.node.lock().unwrap().set_attributes(attrs)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn statvfs(&self) -> io::Result<StatVfs> {
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        MemoryFs::with_limit(DEFAULT_LIMIT)
+    }
+}
+
+impl MemoryFs {
+    /// An empty filesystem holding at most `limit` bytes of file content
+    fn with_limit(limit: u64) -> Self {
+        let usage = Arc::new(Usage {
+            used: AtomicU64::new(0),
+            limit,
+        });
+        MemoryFs {
+            nodes: Mutex::new(HashMap::from([(
+                PathBuf::from("/"),
+                Node::new(None, 0o755, &usage),
+            )])),
+            usage,
+        }
+    }
+
+    fn get(&self, path: &Path) -> io::Result<Arc<Mutex<Node>>> {
+        self.nodes
+            .lock()
+            .unwrap()
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    fn is_dir(&self, path: &Path) -> io::Result<bool> {
+        Ok(self.get(path)?.lock().unwrap().content.is_none())
+    }
+
+    /// Check that the parent of a new file or directory exists
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if self.is_dir(parent)? => Ok(()),
+            Some(_) => Err(ErrorKind::NotADirectory.into()),
+            // the root always exists
+            None => Err(ErrorKind::AlreadyExists.into()),
+        }
+    }
+}
+
+impl Filesystem for MemoryFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileAttributes> {
+        Ok(self.get(path)?.lock().unwrap().attrs())
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileAttributes> {
+        self.metadata(path)
+    }
+
+    fn set_attributes(&self, path: &Path, attrs: &FileAttributes) -> io::Result<()> {
+        self.get(path)?.lock().unwrap().set_attributes(attrs)
+    }
+
+    fn set_symlink_attributes(&self, path: &Path, attrs: &FileAttributes) -> io::Result<()> {
+        self.set_attributes(path, attrs)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        let path = normalize(path);
+        if !self.is_dir(&path)? {
+            return Err(ErrorKind::NotADirectory.into());
+        }
+
+        let nodes = self.nodes.lock().unwrap();
+        let mut entries: Vec<DirEntry> = nodes
+            .iter()
+            .filter(|(child, _)| child.parent() == Some(&path))
+            .filter_map(|(child, node)| {
+                let node = node.lock().unwrap();
+                Some(DirEntry {
+                    name: child.file_name()?.to_string_lossy().to_string(),
+                    attrs: node.attrs(),
+                    nlink: if node.content.is_some() { 1 } else { 2 },
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+
+    fn open(&self, path: &Path, options: OpenOptions) -> io::Result<Box<dyn FileHandle>> {
+        let path = normalize(path);
+        let existing = self.nodes.lock().unwrap().get(&path).cloned();
+        let node = match existing {
+            Some(_) if options.create_new => return Err(ErrorKind::AlreadyExists.into()),
+            Some(node) => {
+                {
+                    let mut node = node.lock().unwrap();
+                    match node.content {
+                        None => return Err(ErrorKind::IsADirectory.into()),
+                        Some(_) if options.truncate => node.resize(0)?,
+                        Some(_) => {}
+                    }
+                }
+                node
+            }
+            None if options.create || options.create_new => {
+                self.check_parent(&path)?;
+                let node = Node::new(
+                    Some(vec![]),
+                    options.mode.unwrap_or(0o644) & 0o7777,
+                    &self.usage,
+                );
+                self.nodes.lock().unwrap().insert(path, node.clone());
+                node
+            }
+            None => return Err(ErrorKind::NotFound.into()),
+        };
+        Ok(Box::new(MemoryHandle { node, options }))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        if self.is_dir(&path)? {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        self.nodes.lock().unwrap().remove(&path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let is_dir = self.is_dir(&from)?;
+        if from == to {
+            return Ok(());
+        }
+        self.check_parent(&to)?;
+        if to.starts_with(&from) {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        if self.get(&to).is_ok() && (is_dir || self.is_dir(&to)?) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+
+        // a directory takes its content along
+        let mut nodes = self.nodes.lock().unwrap();
+        let moved: Vec<PathBuf> = nodes
+            .keys()
+            .filter(|path| path.starts_with(&from))
+            .cloned()
+            .collect();
+        for path in moved {
+            let node = nodes.remove(&path).unwrap();
+            let relative = path.strip_prefix(&from).unwrap();
+            nodes.insert(to.join(relative), node);
+        }
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path, mode: Option<u32>) -> io::Result<()> {
+        let path = normalize(path);
+        self.check_parent(&path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(&path) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        nodes.insert(
+            path,
+            Node::new(None, mode.unwrap_or(0o755) & 0o7777, &self.usage),
+        );
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        if !self.is_dir(&path)? {
+            return Err(ErrorKind::NotADirectory.into());
+        }
+        if path.parent().is_none() {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.keys().any(|child| child.parent() == Some(&path)) {
+            return Err(ErrorKind::DirectoryNotEmpty.into());
+        }
+        nodes.remove(&path);
+        Ok(())
+    }
+
+    fn read_link(&self, _path: &Path) -> io::Result<PathBuf> {
+        Err(ErrorKind::InvalidInput.into())
+    }
+
+    fn symlink(&self, _target: &Path, _link: &Path) -> io::Result<()> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let link = normalize(link);
+        let node = self.get(original)?;
+        if node.lock().unwrap().content.is_none() {
+            return Err(ErrorKind::IsADirectory.into());
+        }
+        self.check_parent(&link)?;
+        // both paths share the node, like an inode
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.contains_key(&link) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        nodes.insert(link, node);
+        Ok(())
+    }
+
+    fn statvfs(&self, _path: &Path) -> io::Result<StatVfs> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        self.get(&path)?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(fs: &MemoryFs, path: &str, data: &[u8]) -> io::Result<()> {
+        let options = OpenOptions {
+            write: true,
+            create: true,
+            ..Default::default()
+        };
+        fs.open(Path::new(path), options)?.write_at(data, 0)?;
+        Ok(())
+    }
+
+    fn read(fs: &MemoryFs, path: &str) -> Vec<u8> {
+        let options = OpenOptions {
+            read: true,
+            ..Default::default()
+        };
+        let file = fs.open(Path::new(path), options).unwrap();
+        let mut buf = vec![0; 64];
+        let len = file.read_at(&mut buf, 0).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn rename_dir_moves_content() {
+        let fs = MemoryFs::default();
+        fs.create_dir(Path::new("/a"), None).unwrap();
+        fs.create_dir(Path::new("/a/b"), None).unwrap();
+        write(&fs, "/a/b/file", b"data").unwrap();
+
+        fs.rename(Path::new("/a"), Path::new("/c")).unwrap();
+        assert_eq!(read(&fs, "/c/b/file"), b"data");
+        assert!(fs.metadata(Path::new("/a")).is_err());
+        assert!(fs.metadata(Path::new("/a/b/file")).is_err());
+
+        // not into itself
+        let err = fs.rename(Path::new("/c"), Path::new("/c/b/d")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn remove_dir_not_empty() {
+        let fs = MemoryFs::default();
+        fs.create_dir(Path::new("/dir"), None).unwrap();
+        write(&fs, "/dir/file", b"").unwrap();
+
+        let err = fs.remove_dir(Path::new("/dir")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DirectoryNotEmpty);
+        fs.remove_file(Path::new("/dir/file")).unwrap();
+        fs.remove_dir(Path::new("/dir")).unwrap();
+        assert_eq!(
+            fs.remove_dir(Path::new("/")).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn truncate() {
+        let fs = MemoryFs::default();
+        write(&fs, "/file", b"0123456789").unwrap();
+
+        let attrs = FileAttributes {
+            size: Some(4),
+            ..Default::default()
+        };
+        fs.set_attributes(Path::new("/file"), &attrs).unwrap();
+        assert_eq!(read(&fs, "/file"), b"0123");
+
+        let options = OpenOptions {
+            write: true,
+            truncate: true,
+            ..Default::default()
+        };
+        fs.open(Path::new("/file"), options).unwrap();
+        assert_eq!(read(&fs, "/file"), b"");
+    }
+
+    #[test]
+    fn size_limit() {
+        let fs = MemoryFs::with_limit(10);
+        write(&fs, "/a", b"012345").unwrap();
+        let err = write(&fs, "/b", b"012345").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+
+        // removed files free their space
+        fs.remove_file(Path::new("/a")).unwrap();
+        write(&fs, "/b", b"0123456789").unwrap();
+
+        let options = OpenOptions {
+            write: true,
+            ..Default::default()
+        };
+        let file = fs.open(Path::new("/b"), options).unwrap();
+        let err = file.write_at(b"x", u64::MAX).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+    }
+}
@@ -0,0 +1,147 @@
+//! Filesystems SFTP sessions operate on
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use russh_sftp::protocol::{FileAttributes, OpenFlags};
+
+mod local;
+mod memory;
+mod overlay;
+
+pub use local::LocalFs;
+pub use memory::MemoryFs;
+pub use overlay::OverlayFs;
+
+/// How to open a file, like `std::fs::OpenOptions`
+#[derive(Clone, Copy, Default, Debug)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub create: bool,
+    pub truncate: bool,
+    // fail if the file already exists
+    pub create_new: bool,
+    // permissions of a created file, before the umask. The filesystem default if None
+    pub mode: Option<u32>,
+}
+
+impl From<OpenFlags> for OpenOptions {
+    fn from(flags: OpenFlags) -> Self {
+        OpenOptions {
+            read: flags.contains(OpenFlags::READ),
+            write: flags.contains(OpenFlags::WRITE),
+            append: flags.contains(OpenFlags::APPEND),
+            create: flags.contains(OpenFlags::CREATE),
+            truncate: flags.contains(OpenFlags::TRUNCATE),
+            create_new: false,
+            mode: None,
+        }
+    }
+}
+
+/// An entry of a directory, with the attributes of the entry itself (symlinks are not followed)
+pub struct DirEntry {
+    // lossy for names that are not UTF-8
+    pub name: String,
+    pub attrs: FileAttributes,
+    pub nlink: u64,
+}
+
+/// Entries of a directory, read as they are consumed
+pub type ReadDir = Box<dyn Iterator<Item = io::Result<DirEntry>> + Send>;
+
+/// Statistics of a filesystem, like `statvfs(3)`
+#[derive(Default, Debug)]
+pub struct StatVfs {
+    pub block_size: u64,
+    pub fragment_size: u64,
+    pub blocks: u64,
+    pub blocks_free: u64,
+    pub blocks_available: u64,
+    pub files: u64,
+    pub files_free: u64,
+    pub files_available: u64,
+    pub fsid: u64,
+    pub flags: u64,
+    pub name_max: u64,
+}
+
+/// A file opened by a client
+pub trait FileHandle: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+    fn write_at(&self, data: &[u8], offset: u64) -> io::Result<usize>;
+    /// Write all of `data`, like `FileExt::write_all_at`
+    fn write_all_at(&self, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.write_at(data, offset)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                written => {
+                    data = &data[written..];
+                    offset += written as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn metadata(&self) -> io::Result<FileAttributes>;
+    fn set_attributes(&self, attrs: &FileAttributes) -> io::Result<()>;
+    /// Wait for the content to be on the disk
+    fn sync(&self) -> io::Result<()>;
+    fn statvfs(&self) -> io::Result<StatVfs>;
+}
+
+/// Operations SFTP needs from a filesystem. Paths are the ones given by the client (after confinement),
+/// relative paths are relative to the starting directory of the filesystem
+pub trait Filesystem: Send + Sync {
+    /// Follows symlinks
+    fn metadata(&self, path: &Path) -> io::Result<FileAttributes>;
+    /// Does not follow symlinks
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileAttributes>;
+    fn set_attributes(&self, path: &Path, attrs: &FileAttributes) -> io::Result<()>;
+    /// Like `set_attributes`, on the symlink itself if `path` is one
+    fn set_symlink_attributes(&self, path: &Path, attrs: &FileAttributes) -> io::Result<()>;
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir>;
+    fn open(&self, path: &Path, options: OpenOptions) -> io::Result<Box<dyn FileHandle>>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// `mode` is applied before the umask, the filesystem default is used if None
+    fn create_dir(&self, path: &Path, mode: Option<u32>) -> io::Result<()>;
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    /// Create `link` pointing to `target`, which is stored as it is and does not have to exist
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()>;
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()>;
+    fn statvfs(&self, path: &Path) -> io::Result<StatVfs>;
+    /// Absolute path without `.`, `..` or symlinks. The file must exist
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+/// Resolve `.` and `..` in a path, relative to `/`. Never goes above `/`
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+fn file_type(attrs: &FileAttributes) -> Option<u32> {
+    attrs.permissions.map(|mode| mode & libc::S_IFMT)
+}
+
+pub fn is_dir(attrs: &FileAttributes) -> bool {
+    file_type(attrs) == Some(libc::S_IFDIR)
+}
+
+pub fn is_symlink(attrs: &FileAttributes) -> bool {
+    file_type(attrs) == Some(libc::S_IFLNK)
+}
@@ -0,0 +1,453 @@
+use std::{
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use russh_sftp::protocol::FileAttributes;
+
+use super::{
+    is_symlink, normalize, DirEntry, FileHandle, Filesystem, OpenOptions, ReadDir, StatVfs,
+};
+
+/// Directories of other filesystems composed into one tree. Directories above the mount points
+/// that no filesystem provides exist as read-only empty directories
+#[derive(Default)]
+pub struct OverlayFs {
+    // deepest mount points first, so the most specific one is found first
+    mounts: Vec<Mount>,
+}
+
+struct Mount {
+    at: PathBuf,
+    fs: Arc<dyn Filesystem>,
+    // directory of `fs` shown at `at`
+    source: PathBuf,
+}
+
+impl Mount {
+    /// Path in the tree of the overlay for a path of the mounted filesystem, if it is inside `source`
+    fn to_overlay(&self, path: &Path) -> Option<PathBuf> {
+        let relative = path.strip_prefix(&self.source).ok()?;
+        Some(self.at.join(relative))
+    }
+
+    /// Refuse paths that symlinks lead outside of `source`, and return the checked path. With
+    /// `follow`, this is where a symlink points. Without it, only the directory holding the entry is
+    /// checked, for operations on the entry itself or that create it
+    fn contain(&self, real: &Path, follow: bool) -> io::Result<PathBuf> {
+        if real == self.source {
+            return Ok(real.to_path_buf());
+        }
+        if follow {
+            match self.fs.canonicalize(real) {
+                Ok(canonical) => return self.check_inside(canonical),
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                Err(_) => {}
+            }
+            // a dangling symlink would be followed when creating a file through it
+            if self
+                .fs
+                .symlink_metadata(real)
+                .is_ok_and(|attrs| is_symlink(&attrs))
+            {
+                log::warn!("Refused dangling symlink {}", real.display());
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+        }
+        let (Some(parent), Some(name)) = (real.parent(), real.file_name()) else {
+            return Err(ErrorKind::PermissionDenied.into());
+        };
+        self.check_inside(self.fs.canonicalize(parent)?.join(name))
+    }
+
+    fn check_inside(&self, canonical: PathBuf) -> io::Result<PathBuf> {
+        if canonical.starts_with(&self.source) {
+            Ok(canonical)
+        } else {
+            log::warn!(
+                "Refused {}: it is outside of the mounted directory {}",
+                canonical.display(),
+                self.source.display()
+            );
+            Err(ErrorKind::PermissionDenied.into())
+        }
+    }
+}
+
+fn virtual_dir() -> FileAttributes {
+    FileAttributes {
+        size: Some(0),
+        uid: Some(unsafe { libc::getuid() }),
+        gid: Some(unsafe { libc::getgid() }),
+        permissions: Some(libc::S_IFDIR | 0o555),
+        ..Default::default()
+    }
+}
+
+impl OverlayFs {
+    /// Show `source` of `fs` at `at`. A later mount at the same place replaces the previous one
+    pub fn mount(&mut self, at: &Path, fs: Arc<dyn Filesystem>, source: &Path) {
+        let at = normalize(at);
+        self.mounts.retain(|mount| mount.at != at);
+        self.mounts.push(Mount {
+            at,
+            fs,
+            source: source.to_path_buf(),
+        });
+        self.mounts
+            .sort_by_key(|mount| std::cmp::Reverse(mount.at.components().count()));
+    }
+
+    /// The mount holding a path, and the path in the mounted filesystem
+    fn resolve(&self, path: &Path) -> Option<(&Mount, PathBuf)> {
+        let path = normalize(path);
+        self.mounts.iter().find_map(|mount| {
+            let relative = path.strip_prefix(&mount.at).ok()?;
+            Some((mount, mount.source.join(relative)))
+        })
+    }
+
+    /// Like `resolve`, with the path checked by `Mount::contain`
+    fn resolve_contained(&self, path: &Path, follow: bool) -> io::Result<(&Mount, PathBuf)> {
+        let (mount, real) = self.resolve(path).ok_or(ErrorKind::NotFound)?;
+        Ok((mount, mount.contain(&real, follow)?))
+    }
+
+    /// Like `resolve_contained`, for operations that modify the tree. Mount points themselves cannot
+    /// be changed
+    fn resolve_mut(&self, path: &Path, follow: bool) -> io::Result<(&Mount, PathBuf)> {
+        let normalized = normalize(path);
+        if self.mounts.iter().any(|mount| mount.at == normalized) {
+            return Err(ErrorKind::PermissionDenied.into());
+        }
+        let (mount, real) = self.resolve(path).ok_or(ErrorKind::PermissionDenied)?;
+        Ok((mount, mount.contain(&real, follow)?))
+    }
+
+    /// Names of the mount points directly inside a directory
+    fn child_mounts(&self, dir: &Path) -> Vec<String> {
+        let dir = normalize(dir);
+        let mut names: Vec<String> = self
+            .mounts
+            .iter()
+            .filter_map(|mount| {
+                let relative = mount.at.strip_prefix(&dir).ok()?;
+                Some(relative.iter().next()?.to_str()?.to_string())
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Call `op` on the filesystem holding `path`, checked like `resolve_contained`. Directories
+    /// leading to mount points exist even if no filesystem has them, `fallback` is used for them
+    fn lookup<T>(
+        &self,
+        path: &Path,
+        follow: bool,
+        op: impl FnOnce(&Mount, &Path) -> io::Result<T>,
+        fallback: impl FnOnce() -> T,
+    ) -> io::Result<T> {
+        let result = self
+            .resolve_contained(path, follow)
+            .and_then(|(mount, real)| op(mount, &real));
+        match result {
+            Err(err)
+                if err.kind() == ErrorKind::NotFound && !self.child_mounts(path).is_empty() =>
+            {
+                Ok(fallback())
+            }
+            result => result,
+        }
+    }
+}
+
+impl Filesystem for OverlayFs {
+    fn metadata(&self, path: &Path) -> io::Result<FileAttributes> {
+        self.lookup(
+            path,
+            true,
+            |mount, real| mount.fs.metadata(real),
+            virtual_dir,
+        )
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<FileAttributes> {
+        self.lookup(
+            path,
+            false,
+            |mount, real| mount.fs.symlink_metadata(real),
+            virtual_dir,
+        )
+    }
+
+    fn set_attributes(&self, path: &Path, attrs: &FileAttributes) -> io::Result<()> {
+        let (mount, real) = self.resolve_mut(path, true)?;
+        mount.fs.set_attributes(&real, attrs)
+    }
+
+    fn set_symlink_attributes(&self, path: &Path, attrs: &FileAttributes) -> io::Result<()> {
+        let (mount, real) = self.resolve_mut(path, false)?;
+        mount.fs.set_symlink_attributes(&real, attrs)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<ReadDir> {
+        let entries = self.lookup(
+            path,
+            true,
+            |mount, real| mount.fs.read_dir(real),
+            || Box::new(std::iter::empty()),
+        )?;
+
+        // mount points hide what is below them
+        let names = self.child_mounts(path);
+        let mut mounts = vec![];
+        for name in &names {
+            let attrs = self.metadata(&normalize(path).join(name))?;
+            mounts.push(Ok(DirEntry {
+                name: name.clone(),
+                attrs,
+                nlink: 2,
+            }));
+        }
+        let entries = entries.filter(move |entry| match entry {
+            Ok(entry) => !names.contains(&entry.name),
+            Err(_) => true,
+        });
+        Ok(Box::new(entries.chain(mounts)))
+    }
+
+    fn open(&self, path: &Path, options: OpenOptions) -> io::Result<Box<dyn FileHandle>> {
+        let (mount, real) = self.resolve_contained(path, true)?;
+        mount.fs.open(&real, options)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let (mount, real) = self.resolve_mut(path, false)?;
+        mount.fs.remove_file(&real)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_mount, from) = self.resolve_mut(from, false)?;
+        let (to_mount, to) = self.resolve_mut(to, false)?;
+        if !std::ptr::eq(from_mount, to_mount) {
+            return Err(ErrorKind::CrossesDevices.into());
+        }
+        from_mount.fs.rename(&from, &to)
+    }
+
+    fn create_dir(&self, path: &Path, mode: Option<u32>) -> io::Result<()> {
+        let (mount, real) = self.resolve_mut(path, false)?;
+        mount.fs.create_dir(&real, mode)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let (mount, real) = self.resolve_mut(path, false)?;
+        mount.fs.remove_dir(&real)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let (mount, real) = self.resolve_contained(path, false)?;
+        let target = mount.fs.read_link(&real)?;
+        if target.is_relative() {
+            return Ok(target);
+        }
+        // absolute targets outside of the mounted directory cannot be shown
+        mount
+            .to_overlay(&target)
+            .ok_or_else(|| ErrorKind::PermissionDenied.into())
+    }
+
+    fn symlink(&self, target: &Path, link: &Path) -> io::Result<()> {
+        let (mount, link) = self.resolve_mut(link, false)?;
+        if target.is_relative() {
+            // resolved from the directory of the link, it must not lead out of the mount
+            let resolved = normalize(&link.parent().unwrap_or(&link).join(target));
+            if !resolved.starts_with(&mount.source) {
+                log::warn!(
+                    "Refused symlink to {}: it leads out of the mount",
+                    target.display()
+                );
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+            return mount.fs.symlink(target, &link);
+        }
+        // absolute targets are paths of the overlay, they have to be in the same mount
+        match self.resolve(target) {
+            Some((target_mount, target)) if std::ptr::eq(mount, target_mount) => {
+                mount.fs.symlink(&target, &link)
+            }
+            _ => Err(ErrorKind::CrossesDevices.into()),
+        }
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let (original_mount, original) = self.resolve_contained(original, false)?;
+        let (link_mount, link) = self.resolve_mut(link, false)?;
+        if !std::ptr::eq(original_mount, link_mount) {
+            return Err(ErrorKind::CrossesDevices.into());
+        }
+        link_mount.fs.hard_link(&original, &link)
+    }
+
+    fn statvfs(&self, path: &Path) -> io::Result<StatVfs> {
+        self.lookup(
+            path,
+            true,
+            |mount, real| mount.fs.statvfs(real),
+            StatVfs::default,
+        )
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.lookup(
+            path,
+            true,
+            |mount, real| {
+                let canonical = mount.fs.canonicalize(real)?;
+                // symlinks leading out of the mounted directory
+                mount
+                    .to_overlay(&canonical)
+                    .ok_or_else(|| ErrorKind::PermissionDenied.into())
+            },
+            || normalize(path),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::ssh::vfs::{is_dir, LocalFs, MemoryFs};
+
+    fn names(fs: &OverlayFs, path: &str) -> Vec<String> {
+        let mut names: Vec<String> = fs
+            .read_dir(Path::new(path))
+            .unwrap()
+            .map(|entry| entry.unwrap().name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn create(fs: &dyn Filesystem, path: &str) -> io::Result<()> {
+        let options = OpenOptions {
+            write: true,
+            create: true,
+            ..Default::default()
+        };
+        fs.open(Path::new(path), options).map(|_| ())
+    }
+
+    #[test]
+    fn virtual_dirs() {
+        let mut overlay = OverlayFs::default();
+        overlay.mount(
+            Path::new("/a/b"),
+            Arc::new(MemoryFs::default()),
+            Path::new("/"),
+        );
+
+        assert!(is_dir(&overlay.metadata(Path::new("/")).unwrap()));
+        assert!(is_dir(&overlay.metadata(Path::new("/a")).unwrap()));
+        assert_eq!(names(&overlay, "/"), ["a"]);
+        assert_eq!(names(&overlay, "/a"), ["b"]);
+        assert_eq!(
+            overlay.metadata(Path::new("/c")).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        // nothing can be created outside of the mounts
+        let err = overlay.create_dir(Path::new("/a/c"), None).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        overlay.create_dir(Path::new("/a/b/c"), None).unwrap();
+        assert_eq!(names(&overlay, "/a/b"), ["c"]);
+    }
+
+    #[test]
+    fn mount_shadows() {
+        let root = Arc::new(MemoryFs::default());
+        root.create_dir(Path::new("/m"), None).unwrap();
+        create(root.as_ref(), "/m/hidden").unwrap();
+        create(root.as_ref(), "/file").unwrap();
+        let mut overlay = OverlayFs::default();
+        overlay.mount(Path::new("/"), root, Path::new("/"));
+        overlay.mount(
+            Path::new("/m"),
+            Arc::new(MemoryFs::default()),
+            Path::new("/"),
+        );
+
+        assert_eq!(names(&overlay, "/"), ["file", "m"]);
+        assert!(names(&overlay, "/m").is_empty());
+        assert_eq!(
+            overlay.metadata(Path::new("/m/hidden")).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        // the mount point itself cannot be changed
+        let err = overlay.remove_dir(Path::new("/m")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn rename_across_mounts() {
+        let mut overlay = OverlayFs::default();
+        overlay.mount(
+            Path::new("/"),
+            Arc::new(MemoryFs::default()),
+            Path::new("/"),
+        );
+        overlay.mount(
+            Path::new("/m"),
+            Arc::new(MemoryFs::default()),
+            Path::new("/"),
+        );
+        create(&overlay, "/file").unwrap();
+
+        let err = overlay
+            .rename(Path::new("/file"), Path::new("/m/file"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::CrossesDevices);
+        overlay
+            .rename(Path::new("/file"), Path::new("/renamed"))
+            .unwrap();
+        assert_eq!(names(&overlay, "/"), ["m", "renamed"]);
+    }
+
+    #[test]
+    fn symlinks_stay_in_mount() {
+        let dir = std::env::temp_dir().join(format!("quickssh-overlay-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("inside")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::write(dir.join("outside/secret"), "secret").unwrap();
+        std::os::unix::fs::symlink("../outside", dir.join("inside/out")).unwrap();
+        std::os::unix::fs::symlink("../outside/new", dir.join("inside/dangling")).unwrap();
+        let source = fs::canonicalize(dir.join("inside")).unwrap();
+        let mut overlay = OverlayFs::default();
+        overlay.mount(Path::new("/"), Arc::new(LocalFs), &source);
+
+        let denied = |result: io::Result<()>| {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied)
+        };
+        denied(overlay.metadata(Path::new("/out/secret")).map(|_| ()));
+        denied(overlay.read_dir(Path::new("/out")).map(|_| ()));
+        denied(create(&overlay, "/out/new"));
+        denied(create(&overlay, "/dangling"));
+        denied(overlay.symlink(Path::new("../outside"), Path::new("/link")));
+        denied(overlay.symlink(Path::new("a/../../outside"), Path::new("/link")));
+        // the links themselves can be seen and removed
+        overlay.symlink_metadata(Path::new("/out")).unwrap();
+        overlay
+            .symlink(Path::new("sub/file"), Path::new("/link"))
+            .unwrap();
+        overlay.remove_file(Path::new("/out")).unwrap();
+        assert!(!dir.join("outside/new").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
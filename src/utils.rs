@@ -1,7 +1,98 @@
-use std::env;
+use std::{env, time::Duration};
 
 use anyhow::Context;
 
 pub fn get_username() -> anyhow::Result<String> {
     env::var("USER").context("Failed to read USER env variable")
 }
+
+pub fn get_hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return "localhost".to_string();
+    }
+    let len = buf.iter().position(|byte| *byte == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+/// Parse a duration such as `90`, `90s`, `15m`, `2h` or `1d`
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid duration: {s}"))?;
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => anyhow::bail!("Invalid duration unit in {s} (expected s, m, h or d)"),
+    };
+    let secs = number
+        .checked_mul(multiplier)
+        .with_context(|| format!("Duration too long: {s}"))?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// Inverse of parse_duration()
+pub fn format_duration(duration: Duration) -> String {
+    format!("{}s", duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_have_units() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(
+            parse_duration("2h").unwrap(),
+            Duration::from_secs(2 * 60 * 60)
+        );
+        assert_eq!(
+            parse_duration("1d").unwrap(),
+            Duration::from_secs(24 * 60 * 60)
+        );
+        assert!(parse_duration("3w").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn durations_default_to_seconds() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration(" 90 ").unwrap(), Duration::from_secs(90));
+    }
+
+    #[test]
+    fn zero_duration() {
+        assert_eq!(parse_duration("0").unwrap(), Duration::ZERO);
+        assert_eq!(parse_duration("0h").unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn duration_overflow() {
+        assert!(parse_duration(&format!("{}d", u64::MAX / 60)).is_err());
+        assert!(parse_duration("99999999999999999999").is_err());
+        assert_eq!(
+            parse_duration(&format!("{}", u64::MAX)).unwrap(),
+            Duration::from_secs(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn formatted_durations_parse_back() {
+        let duration = Duration::from_secs(3600);
+        assert_eq!(
+            parse_duration(&format_duration(duration)).unwrap(),
+            duration
+        );
+    }
+}